@@ -0,0 +1,170 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use greenthread_future::{
+    block_on, out_of_band, out_of_band_in_place, yield_now, DetachedThread, ThreadFuture,
+};
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::time::Instant;
+
+/// Number of samples taken for the min/median/max report.
+const SAMPLES: usize = 1000;
+/// Number of round-trips measured in each sample.
+const BATCH: u32 = 1000;
+
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+/// Each poll switches into a thread which yields immediately,
+/// so it measures exactly one `yield_now` round-trip.
+fn yield_round_trip(c: &mut Criterion) {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut thread = Box::new(ThreadFuture::from(|| loop {
+        yield_now();
+    }));
+    c.bench_function("yield_now round-trip", |b| {
+        b.iter(|| Pin::new(&mut *thread).poll(&mut cx))
+    });
+}
+
+/// Report min/median/max of the per round-trip latency.
+///
+/// Criterion reports the confidence interval of the mean,
+/// but the spread is what matters to a scheduler.
+fn yield_round_trip_spread(_: &mut Criterion) {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut thread = Box::new(ThreadFuture::from(|| loop {
+        yield_now();
+    }));
+    let mut samples: Vec<f64> = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..BATCH {
+                let _ = Pin::new(&mut *thread).poll(&mut cx);
+            }
+            start.elapsed().as_nanos() as f64 / BATCH as f64
+        })
+        .collect();
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!(
+        "yield_now round-trip: min {:.1} ns, median {:.1} ns, max {:.1} ns ({} x {} iterations)",
+        samples[0],
+        samples[SAMPLES / 2],
+        samples[SAMPLES - 1],
+        SAMPLES,
+        BATCH,
+    );
+}
+
+/// Number of threads switched in turn by `many_threads`.
+const THREADS: usize = 64;
+
+/// Switch through many threads in turn, with and without stack coloring.
+///
+/// Without coloring, the hot frames of all threads map to the same cache sets.
+fn many_threads(c: &mut Criterion) {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    for &colors in [1, 16].iter() {
+        let mut threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let thread = ThreadFuture::from(|| loop {
+                    yield_now();
+                });
+                Box::new(thread.with_stack_color(i % colors))
+            })
+            .collect();
+        let name = format!("yield through {} threads, {} colors", THREADS, colors);
+        c.bench_function(&name, |b| {
+            b.iter(|| {
+                for thread in threads.iter_mut() {
+                    let _ = Pin::new(&mut **thread).poll(&mut cx);
+                }
+            })
+        });
+    }
+}
+
+/// Compare a whole thread lifetime with and without a return value.
+fn run_to_exit(c: &mut Criterion) {
+    c.bench_function("detached thread", |b| {
+        b.iter(|| {
+            let thread: DetachedThread<_> = ThreadFuture::from(yield_now);
+            block_on(thread)
+        })
+    });
+    c.bench_function("thread returning [u64; 8]", |b| {
+        b.iter(|| {
+            block_on(ThreadFuture::from(|| {
+                yield_now();
+                [0u64; 8]
+            }))
+        })
+    });
+}
+
+/// Poll a future of threads which never park until it is ready.
+fn run<Fut: Future>(fut: Fut) -> Fut::Output {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = Box::pin(fut);
+    loop {
+        if let Poll::Ready(ret) = fut.as_mut().poll(&mut cx) {
+            return ret;
+        }
+    }
+}
+
+/// Compare returning a large value by copying it into the slot
+/// with constructing it in place.
+fn out_of_band_return(c: &mut Criterion) {
+    let mut slot = MaybeUninit::<[u64; 512]>::uninit();
+    c.bench_function("out_of_band [u64; 512]", |b| {
+        b.iter(|| {
+            run(out_of_band(
+                || {
+                    let mut ret = [0u64; 512];
+                    for (i, x) in ret.iter_mut().enumerate() {
+                        *x = i as u64;
+                    }
+                    ret
+                },
+                &mut slot,
+            ))[0]
+        })
+    });
+    c.bench_function("out_of_band_in_place [u64; 512]", |b| {
+        b.iter(|| {
+            let fut = unsafe {
+                out_of_band_in_place(
+                    |ptr: *mut [u64; 512]| {
+                        for i in 0..512 {
+                            (ptr as *mut u64).add(i).write(i as u64);
+                        }
+                    },
+                    &mut slot,
+                )
+            };
+            run(fut)[0]
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    yield_round_trip,
+    yield_round_trip_spread,
+    many_threads,
+    run_to_exit,
+    out_of_band_return
+);
+criterion_main!(benches);
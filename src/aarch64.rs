@@ -1,3 +1,8 @@
+/// Bytes reserved at the top of a new stack, above the initial context.
+///
+/// The stack top is already 16-byte aligned as the ABI requires.
+const STACK_TOP_RESERVED: usize = 0;
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct ThreadContext {
@@ -5,6 +10,31 @@ pub struct ThreadContext {
     lr: usize,
 }
 
+/// Offsets of the saved registers in `ThreadContext`.
+const CONTEXT_REGISTERS: &[(&str, usize)] = &[
+    ("x19", 0),
+    ("x20", 8),
+    ("x21", 16),
+    ("x22", 24),
+    ("x23", 32),
+    ("x24", 40),
+    ("x25", 48),
+    ("x26", 56),
+    ("x27", 64),
+    ("x28", 72),
+    ("x29", 80),
+    ("lr", 88),
+];
+
+/// Offset of the program counter in `ThreadContext`.
+const CONTEXT_PC_OFFSET: usize = 88;
+
+/// Offset of the frame pointer in `ThreadContext`.
+const CONTEXT_FP_OFFSET: usize = 80;
+
+/// Offset of the entry of a new thread in its initial context.
+const CONTEXT_ENTRY_OFFSET: usize = 8;
+
 impl ThreadContext {
     /// Switch context to another thread.
     #[naked]
@@ -40,6 +70,225 @@ impl ThreadContext {
     fn set_pc(&mut self, pc: usize) {
         self.lr = pc;
     }
+
+    /// Get value of program counter.
+    fn pc(&self) -> usize {
+        self.lr
+    }
+
+    /// Set value of frame pointer.
+    fn set_fp(&mut self, fp: usize) {
+        self.x19to29[10] = fp;
+    }
+
+    /// Make the initial context enter `pc` with `arg` in the first argument register.
+    ///
+    /// `switch` only restores callee-saved registers, so they are parked in
+    /// `x20` and `x19`, and the first switch returns to `trampoline` to move
+    /// them into place.
+    fn set_entry(&mut self, pc: usize, arg: usize) {
+        self.lr = Self::trampoline as usize;
+        self.x19to29[1] = pc;
+        self.x19to29[0] = arg;
+    }
+
+    /// Jump to the entry of a new thread set by `set_entry`.
+    ///
+    /// The link register is cleared to end the frame chain at the entry.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "C" fn trampoline() {
+        asm!(
+        "
+        mov x0, x19
+        mov lr, xzr
+        br x20
+        " : : : : "volatile" );
+    }
+}
+
+/// Optional registers of a thread, swapped in `switch_ext` by its `RegSet`.
+///
+/// The low halves of v8-v15 are callee-saved, and shared by scalar floating
+/// point and SIMD code, so both `FP` and `VECTOR` save d8-d15 with `fpcr`.
+/// `TLS` is `tpidr_el0`.
+///
+/// `switch_ext` accesses the fields by offset: `d8to15` at 0, `fpcr` at 64
+/// and `tpidr` at 72.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ExtContext {
+    d8to15: [u64; 8],
+    fpcr: usize,
+    tpidr: usize,
+}
+
+impl ThreadContext {
+    /// Call the `switch` at `_switch` with the register groups in the bits of
+    /// `_regs` swapped: the executor ones are kept on this stack, and the
+    /// thread ones are loaded from `_ext` before and saved to it after.
+    ///
+    /// It is one asm block, so the compiler cannot allocate d8-d15 between
+    /// loading the values of the thread and switching to it.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "C" fn switch_ext(
+        _ptr_ptr: *mut *mut Self,
+        _ext: *mut ExtContext,
+        _regs: usize,
+        _switch: usize,
+    ) {
+        asm!(
+        "
+        stp x29, lr, [sp, #-16]!
+        stp x19, x20, [sp, #-16]!
+        stp x21, x22, [sp, #-16]!
+        // the executor copy of `ExtContext`
+        sub sp, sp, #80
+
+        mov x19, x1
+        mov x20, x2
+        mov x21, x0
+        mov x22, x3
+
+        // FP and VECTOR share d8-d15
+        tst x20, #2
+        b.ne 1f
+        tst x20, #8
+        b.eq 2f
+    1:
+        stp d8, d9, [sp]
+        stp d10, d11, [sp, #16]
+        stp d12, d13, [sp, #32]
+        stp d14, d15, [sp, #48]
+        mrs x9, fpcr
+        str x9, [sp, #64]
+        ldp d8, d9, [x19]
+        ldp d10, d11, [x19, #16]
+        ldp d12, d13, [x19, #32]
+        ldp d14, d15, [x19, #48]
+        ldr x9, [x19, #64]
+        msr fpcr, x9
+    2:
+        tst x20, #4
+        b.eq 3f
+        mrs x9, tpidr_el0
+        str x9, [sp, #72]
+        ldr x9, [x19, #72]
+        msr tpidr_el0, x9
+    3:
+        mov x0, x21
+        blr x22
+
+        tst x20, #2
+        b.ne 4f
+        tst x20, #8
+        b.eq 5f
+    4:
+        stp d8, d9, [x19]
+        stp d10, d11, [x19, #16]
+        stp d12, d13, [x19, #32]
+        stp d14, d15, [x19, #48]
+        mrs x9, fpcr
+        str x9, [x19, #64]
+        ldp d8, d9, [sp]
+        ldp d10, d11, [sp, #16]
+        ldp d12, d13, [sp, #32]
+        ldp d14, d15, [sp, #48]
+        ldr x9, [sp, #64]
+        msr fpcr, x9
+    5:
+        tst x20, #4
+        b.eq 6f
+        mrs x9, tpidr_el0
+        str x9, [x19, #72]
+        ldr x9, [sp, #72]
+        msr tpidr_el0, x9
+    6:
+        add sp, sp, #80
+        ldp x21, x22, [sp], #16
+        ldp x19, x20, [sp], #16
+        ldp x29, lr, [sp], #16
+        " : : : : "volatile" );
+    }
+}
+
+impl ExtContext {
+    /// Save the register groups in `regs` from the CPU, which a new thread
+    /// starts with.
+    #[inline(always)]
+    unsafe fn save(&mut self, regs: RegSet) {
+        if regs.intersects(RegSet::FP | RegSet::VECTOR) {
+            asm!(
+            "
+            stp d8, d9, [$0]
+            stp d10, d11, [$0, #16]
+            stp d12, d13, [$0, #32]
+            stp d14, d15, [$0, #48]
+            " : : "r"(self.d8to15.as_mut_ptr()) : "memory" : "volatile" );
+            asm!("mrs $0, fpcr" : "=r"(self.fpcr) : : : "volatile" );
+        }
+        if regs.contains(RegSet::TLS) {
+            asm!("mrs $0, tpidr_el0" : "=r"(self.tpidr) : : : "volatile" );
+        }
+    }
+}
+
+/// Number of registers set by `switch_marked`.
+#[cfg(test)]
+const CALLEE_SAVED: usize = 11;
+
+/// Set callee-saved registers to `base + i` in the order of `ThreadContext`,
+/// call `switch` with `ptr_ptr`, then store the registers found after
+/// switching back to `out`. It is used by the conformance tests.
+#[cfg(test)]
+#[naked]
+#[inline(never)]
+unsafe extern "C" fn switch_marked(
+    _ptr_ptr: *mut *mut ThreadContext,
+    _out: *mut usize,
+    _base: usize,
+    _switch: usize,
+) {
+    asm!(
+    "
+    stp x29, lr, [sp, #-16]!
+    stp x27, x28, [sp, #-16]!
+    stp x25, x26, [sp, #-16]!
+    stp x23, x24, [sp, #-16]!
+    stp x21, x22, [sp, #-16]!
+    stp x19, x20, [sp, #-16]!
+    // save `out`
+    str x1, [sp, #-16]!
+
+    mov x19, x2
+    add x20, x2, #1
+    add x21, x2, #2
+    add x22, x2, #3
+    add x23, x2, #4
+    add x24, x2, #5
+    add x25, x2, #6
+    add x26, x2, #7
+    add x27, x2, #8
+    add x28, x2, #9
+    add x29, x2, #10
+    blr x3
+
+    ldr x1, [sp], #16
+    stp x19, x20, [x1]
+    stp x21, x22, [x1, #16]
+    stp x23, x24, [x1, #32]
+    stp x25, x26, [x1, #48]
+    stp x27, x28, [x1, #64]
+    str x29, [x1, #80]
+
+    ldp x19, x20, [sp], #16
+    ldp x21, x22, [sp], #16
+    ldp x23, x24, [sp], #16
+    ldp x25, x26, [sp], #16
+    ldp x27, x28, [sp], #16
+    ldp x29, lr, [sp], #16
+    " : : : : "volatile" );
 }
 
 /// Get stack pointer.
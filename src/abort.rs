@@ -0,0 +1,94 @@
+//! Cooperative abort of threads from other tasks.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+
+/// Payload of the unwind started by an aborted thread.
+#[derive(Debug)]
+pub struct Aborted;
+
+/// Shared by a thread and its [`AbortHandle`]s.
+pub(crate) struct AbortState {
+    flag: AtomicBool,
+    /// Waker of the thread, known after its first poll.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl AbortState {
+    pub fn new(waker: Option<Waker>) -> Self {
+        AbortState {
+            flag: AtomicBool::new(false),
+            waker: Mutex::new(waker),
+        }
+    }
+
+    pub fn set_waker(&self, waker: Waker) {
+        *self.waker.lock().unwrap() = Some(waker);
+    }
+
+    /// Unwind the current thread if it has been aborted.
+    pub fn check(&self) {
+        if self.flag.load(Ordering::Acquire) {
+            // skip the panic hook, which needs more stack than a thread has
+            std::panic::resume_unwind(std::boxed::Box::new(Aborted));
+        }
+    }
+}
+
+/// A token to abort a thread from another task or CPU.
+///
+/// Created by [`ThreadFuture::abort_handle`](crate::ThreadFuture::abort_handle).
+#[derive(Clone)]
+pub struct AbortHandle {
+    pub(crate) state: Arc<AbortState>,
+}
+
+impl AbortHandle {
+    /// Abort the thread.
+    ///
+    /// The abort is cooperative: the thread unwinds its stack at its next
+    /// yield or park, running destructors on the way, and a parked thread is
+    /// woken to do so. Polling it then resumes the unwind with an [`Aborted`]
+    /// payload on the executor.
+    pub fn abort(&self) {
+        self.state.flag.store(true, Ordering::Release);
+        if let Some(waker) = self.state.waker.lock().unwrap().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{park, ThreadFuture};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn abort_parked_thread() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Guard;
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let mut thread = ThreadFuture::from(|| {
+            let _guard = Guard;
+            loop {
+                park();
+            }
+        });
+        let handle = thread.abort_handle();
+        let task = tokio::spawn(thread);
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            handle.abort();
+        });
+        let err = task.await.unwrap_err();
+        assert!(err.is_panic());
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,366 @@
+//! Actor threads with a request channel and a response channel.
+
+use crate::sync::SpinLock;
+use crate::{current_waker, park, ThreadFuture};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+/// A one-value channel, used by a green thread on one side and a task on the other.
+struct Slot<T> {
+    inner: SpinLock<SlotInner<T>>,
+}
+
+struct SlotInner<T> {
+    value: Option<T>,
+    /// Set when either end is dropped.
+    closed: bool,
+    /// The sender waiting for the slot to be empty.
+    tx_waker: Option<Waker>,
+    /// The receiver waiting for a value.
+    rx_waker: Option<Waker>,
+}
+
+impl<T> Slot<T> {
+    const fn new() -> Self {
+        Slot {
+            inner: SpinLock::new(SlotInner {
+                value: None,
+                closed: false,
+                tx_waker: None,
+                rx_waker: None,
+            }),
+        }
+    }
+
+    /// Put `value` into the slot if it is empty. Returns it back if the receiver is gone.
+    fn poll_send(&self, waker: &Waker, value: &mut Option<T>) -> Poll<Result<(), T>> {
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Poll::Ready(Err(value.take().unwrap()));
+        }
+        if inner.value.is_some() {
+            inner.tx_waker = Some(waker.clone());
+            return Poll::Pending;
+        }
+        inner.value = value.take();
+        let rx_waker = inner.rx_waker.take();
+        drop(inner);
+        if let Some(waker) = rx_waker {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Take the value in the slot. Returns `None` if it is empty and the sender is gone.
+    fn poll_recv(&self, waker: &Waker) -> Poll<Option<T>> {
+        let mut inner = self.inner.lock();
+        if let Some(value) = inner.value.take() {
+            let tx_waker = inner.tx_waker.take();
+            drop(inner);
+            if let Some(waker) = tx_waker {
+                waker.wake();
+            }
+            return Poll::Ready(Some(value));
+        }
+        if inner.closed {
+            return Poll::Ready(None);
+        }
+        inner.rx_waker = Some(waker.clone());
+        Poll::Pending
+    }
+
+    /// Empty the slot and open it again, for a new actor.
+    fn reset(&self) {
+        let mut inner = self.inner.lock();
+        inner.value = None;
+        inner.closed = false;
+        inner.tx_waker = None;
+        inner.rx_waker = None;
+    }
+
+    /// Mark the channel closed, waking the other end.
+    fn close(&self) {
+        let mut inner = self.inner.lock();
+        inner.closed = true;
+        let wakers = (inner.tx_waker.take(), inner.rx_waker.take());
+        drop(inner);
+        if let Some(waker) = wakers.0 {
+            waker.wake();
+        }
+        if let Some(waker) = wakers.1 {
+            waker.wake();
+        }
+    }
+}
+
+/// Storage of the channels of an actor thread created by [`actor`].
+///
+/// Each channel holds one value at a time. A mailbox serves one actor at a
+/// time: it can be reused once all four ends of the last actor are dropped.
+pub struct Mailbox<Req, Resp> {
+    requests: Slot<Req>,
+    responses: Slot<Resp>,
+    /// Number of live ends.
+    ends: AtomicUsize,
+}
+
+impl<Req, Resp> Mailbox<Req, Resp> {
+    /// Create a mailbox, e.g. in a `static`.
+    pub const fn new() -> Self {
+        Mailbox {
+            requests: Slot::new(),
+            responses: Slot::new(),
+            ends: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<Req, Resp> Default for Mailbox<Req, Resp> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The receiving end inside an actor thread.
+pub struct Receiver<T: 'static> {
+    slot: &'static Slot<T>,
+    ends: &'static AtomicUsize,
+}
+
+/// The sending end inside an actor thread.
+pub struct Sender<T: 'static> {
+    slot: &'static Slot<T>,
+    ends: &'static AtomicUsize,
+}
+
+/// The sending end of a task talking to an actor thread.
+pub struct AsyncSender<T: 'static> {
+    slot: &'static Slot<T>,
+    ends: &'static AtomicUsize,
+}
+
+/// The receiving end of a task talking to an actor thread.
+pub struct AsyncReceiver<T: 'static> {
+    slot: &'static Slot<T>,
+    ends: &'static AtomicUsize,
+}
+
+/// Create an actor thread running `f`, with channels in `mailbox`.
+///
+/// `f` gets the receiver of requests and the sender of responses, and
+/// blocks on them by parking. The caller gets the opposite ends, which are
+/// awaited from a task. Dropping an end closes its channel.
+///
+/// Panics if an end of a previous actor on `mailbox` is still alive.
+pub fn actor<G, R, Req, Resp>(
+    mailbox: &'static Mailbox<Req, Resp>,
+    f: G,
+) -> (
+    ThreadFuture<impl Send + 'static + Unpin + FnOnce() -> R, R>,
+    AsyncSender<Req>,
+    AsyncReceiver<Resp>,
+)
+where
+    G: Send + 'static + Unpin + FnOnce(Receiver<Req>, Sender<Resp>) -> R,
+    R: Send + 'static,
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    let ends = &mailbox.ends;
+    assert!(
+        ends.compare_exchange(0, 4, Ordering::AcqRel, Ordering::Acquire).is_ok(),
+        "the mailbox is used by another actor"
+    );
+    // the channels of the last actor are closed, and may hold a stale value
+    mailbox.requests.reset();
+    mailbox.responses.reset();
+    let rx = Receiver {
+        slot: &mailbox.requests,
+        ends,
+    };
+    let tx = Sender {
+        slot: &mailbox.responses,
+        ends,
+    };
+    let thread = ThreadFuture::from(move || f(rx, tx));
+    let tx = AsyncSender {
+        slot: &mailbox.requests,
+        ends,
+    };
+    let rx = AsyncReceiver {
+        slot: &mailbox.responses,
+        ends,
+    };
+    (thread, tx, rx)
+}
+
+impl<T: 'static> Receiver<T> {
+    /// Receive a value, blocking until one is sent.
+    ///
+    /// Returns `None` after the sender is dropped.
+    /// Must be called inside a green thread.
+    pub fn recv(&self) -> Option<T> {
+        loop {
+            if let Poll::Ready(value) = self.slot.poll_recv(&current_waker()) {
+                return value;
+            }
+            park();
+        }
+    }
+}
+
+impl<T: 'static> Sender<T> {
+    /// Send a value, blocking until the channel has room for it.
+    ///
+    /// Returns the value back if the receiver is dropped.
+    /// Must be called inside a green thread.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        loop {
+            if let Poll::Ready(ret) = self.slot.poll_send(&current_waker(), &mut value) {
+                return ret;
+            }
+            park();
+        }
+    }
+}
+
+impl<T: 'static> AsyncSender<T> {
+    /// Send a value, waiting until the channel has room for it.
+    ///
+    /// Resolves to the value back if the receiver is dropped.
+    pub fn send(&self, value: T) -> impl Future<Output = Result<(), T>> + '_ {
+        SendFuture {
+            slot: self.slot,
+            value: Some(value),
+        }
+    }
+}
+
+impl<T: 'static> AsyncReceiver<T> {
+    /// Receive a value, waiting until one is sent.
+    ///
+    /// Resolves to `None` after the sender is dropped.
+    pub fn recv(&self) -> impl Future<Output = Option<T>> + '_ {
+        RecvFuture { slot: self.slot }
+    }
+}
+
+/// Future for [`AsyncSender::send`].
+struct SendFuture<T: 'static> {
+    slot: &'static Slot<T>,
+    value: Option<T>,
+}
+
+impl<T: 'static> Future for SendFuture<T> {
+    type Output = Result<(), T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `value` is never pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        this.slot.poll_send(cx.waker(), &mut this.value)
+    }
+}
+
+/// Future for [`AsyncReceiver::recv`].
+struct RecvFuture<T: 'static> {
+    slot: &'static Slot<T>,
+}
+
+impl<T: 'static> Future for RecvFuture<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.slot.poll_recv(cx.waker())
+    }
+}
+
+impl<T: 'static> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.slot.close();
+        self.ends.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<T: 'static> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.slot.close();
+        self.ends.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<T: 'static> Drop for AsyncSender<T> {
+    fn drop(&mut self) {
+        self.slot.close();
+        self.ends.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<T: 'static> Drop for AsyncReceiver<T> {
+    fn drop(&mut self) {
+        self.slot.close();
+        self.ends.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn echo() {
+        static MAILBOX: Mailbox<u32, u32> = Mailbox::new();
+        let (thread, tx, rx) = actor(&MAILBOX, |rx: Receiver<u32>, tx: Sender<u32>| {
+            let mut count = 0;
+            while let Some(value) = rx.recv() {
+                tx.send(value * 2).unwrap();
+                count += 1;
+            }
+            count
+        });
+        let thread = tokio::spawn(thread);
+        for i in 1..=3 {
+            tx.send(i).await.unwrap();
+            assert_eq!(rx.recv().await, Some(i * 2));
+        }
+        drop(tx);
+        assert_eq!(thread.await.unwrap(), 3);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    fn echo_once(rx: Receiver<u32>, tx: Sender<u32>) {
+        if let Some(value) = rx.recv() {
+            let _ = tx.send(value + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn reuse_mailbox() {
+        static MAILBOX: Mailbox<u32, u32> = Mailbox::new();
+        for i in 0..2 {
+            let (thread, tx, rx) = actor(&MAILBOX, echo_once);
+            let thread = tokio::spawn(thread);
+            tx.send(i).await.unwrap();
+            assert_eq!(rx.recv().await, Some(i + 1));
+            thread.await.unwrap();
+        }
+        // a stale value is not seen by the next actor
+        let (thread, tx, rx) = actor(&MAILBOX, echo_once);
+        tx.send(7).await.unwrap();
+        drop((thread, tx, rx));
+        let (thread, tx, rx) = actor(&MAILBOX, echo_once);
+        drop(tx);
+        tokio::spawn(thread).await.unwrap();
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "the mailbox is used by another actor")]
+    fn share_mailbox() {
+        static MAILBOX: Mailbox<u32, u32> = Mailbox::new();
+        let _first = actor(&MAILBOX, echo_once);
+        let _second = actor(&MAILBOX, echo_once);
+    }
+}
@@ -0,0 +1,60 @@
+//! Zero-copy handoff of buffers from the executor to a thread.
+
+use crate::{ThreadFuture, TCB};
+use core::ptr::NonNull;
+
+/// Take the buffer handed over to the current thread, if any.
+///
+/// The executor hands it over by [`ThreadFuture::put_buffer`], e.g. a DMA
+/// buffer just filled by a device. The thread usually [`park`](crate::park)s
+/// until it gets one, then processes it in place.
+///
+/// Must be called inside a green thread.
+pub fn take_buffer() -> Option<&'static mut [u8]> {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current();
+        tcb.hints.buffer.take().map(|buf| &mut *buf.as_ptr())
+    }
+}
+
+impl<F, T> ThreadFuture<F, T> {
+    /// Hand `buf` over to the thread without copying.
+    ///
+    /// The thread takes it by [`take_buffer`]. Returns the buffer handed
+    /// over before if the thread has not taken it. The thread is not woken.
+    pub fn put_buffer(&mut self, buf: &'static mut [u8]) -> Option<&'static mut [u8]> {
+        let old = unsafe { (*self.tcb).hints.buffer.replace(NonNull::from(buf)) };
+        old.map(|buf| unsafe { &mut *buf.as_ptr() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::park;
+    use crate::tests::noop_waker;
+    use core::task::{Context, Poll};
+
+    #[test]
+    fn sum_buffer_in_place() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| loop {
+            if let Some(buf) = take_buffer() {
+                let sum: u32 = buf.iter().map(|&x| x as u32).sum();
+                buf[0] = 0xff;
+                break (sum, buf.as_ptr() as usize);
+            }
+            park();
+        }));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+
+        let buf = Box::leak(vec![1u8, 2, 3, 4].into_boxed_slice());
+        let addr = buf.as_ptr() as usize;
+        assert!(thread.put_buffer(buf).is_none());
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready((10, addr)));
+        // processed in place
+        assert_eq!(unsafe { *(addr as *const u8) }, 0xff);
+    }
+}
@@ -0,0 +1,109 @@
+//! Blocking functions called with owned arguments, awaited directly.
+
+use crate::ThreadFuture;
+use core::future::IntoFuture;
+
+/// A blocking function and its arguments, which runs on a green thread when awaited.
+///
+/// It is built by [`call`], and turns into a [`ThreadFuture`] by `.await`,
+/// so call sites need not name the thread type.
+pub struct Call<G, A> {
+    f: G,
+    args: A,
+    name: Option<&'static str>,
+}
+
+/// The function of a [`Call`] bound to its arguments, as the closure of the thread.
+pub struct Bound<G, A> {
+    f: G,
+    args: A,
+}
+
+/// Call the blocking function `f` with `args` on a new green thread.
+///
+/// The thread is created when the result is awaited:
+///
+/// ```
+/// use greenthread_future::{call, yield_now};
+///
+/// fn add((a, b): (u32, u32)) -> u32 {
+///     yield_now();
+///     a + b
+/// }
+///
+/// # async fn run() {
+/// assert_eq!(call(add, (1, 2)).await, 3);
+/// # }
+/// ```
+pub fn call<G, A, T>(f: G, args: A) -> Call<G, A>
+where
+    G: FnOnce(A) -> T,
+{
+    Call {
+        f,
+        args,
+        name: None,
+    }
+}
+
+impl<G, A> Call<G, A> {
+    /// Set the name of the thread.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
+}
+
+impl<G, A, T> FnOnce<()> for Bound<G, A>
+where
+    G: FnOnce(A) -> T,
+{
+    type Output = T;
+
+    extern "rust-call" fn call_once(self, _: ()) -> T {
+        (self.f)(self.args)
+    }
+}
+
+impl<G, A, T> IntoFuture for Call<G, A>
+where
+    G: Send + 'static + Unpin + FnOnce(A) -> T,
+    A: Send + 'static + Unpin,
+    T: Send + 'static,
+{
+    type Output = T;
+    type Future = ThreadFuture<Bound<G, A>, T>;
+
+    fn into_future(self) -> Self::Future {
+        let thread = ThreadFuture::from(Bound {
+            f: self.f,
+            args: self.args,
+        });
+        match self.name {
+            Some(name) => thread.with_name(name),
+            None => thread,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{current_thread_name, yield_now};
+
+    fn concat((mut s, n): (String, usize)) -> String {
+        for _ in 0..n {
+            s.push('!');
+            yield_now();
+        }
+        s
+    }
+
+    #[tokio::test]
+    async fn await_call() {
+        let s = call(concat, (String::from("hi"), 2)).await;
+        assert_eq!(s, "hi!!");
+        let name = call(|()| current_thread_name(), ()).with_name("call").await;
+        assert_eq!(name, Some("call"));
+    }
+}
@@ -0,0 +1,151 @@
+//! Checkpoint and restore of parked threads.
+
+use crate::{State, ThreadFuture, RAW_SIZE, STACK_BYTES, TCB};
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use core::sync::atomic::Ordering;
+use std::boxed::Box;
+
+/// A copy of the memory of a parked thread, taken by
+/// [`ThreadFuture::checkpoint`] and resumed by [`ThreadFuture::restore`].
+pub struct StackImage<F, T> {
+    bytes: Box<[u8]>,
+    /// The base address of the thread when it was taken.
+    base: usize,
+    /// The offset of the saved context from the base.
+    context: usize,
+    _thread: PhantomData<ThreadFuture<F, T>>,
+}
+
+impl<F, T> StackImage<F, T> {
+    /// The base address of the thread the image was taken from.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+}
+
+impl<F, T> ThreadFuture<F, T> {
+    /// Copy the TCB, the stack and the saved context of the parked thread.
+    ///
+    /// Panics if the thread is not parked, i.e. it is not polled yet,
+    /// has exited or is running, or if it is blocked on a synchronization
+    /// primitive such as a [`GreenMutex`](crate::GreenMutex).
+    pub fn checkpoint(&self) -> StackImage<F, T> {
+        unsafe {
+            let parked = match &self.tcb.state {
+                State::Running => !self.tcb.on_cpu,
+                _ => false,
+            };
+            assert!(parked, "checkpoint a thread which is not parked");
+            // the queue would hold the node of the original only
+            assert!(
+                self.tcb.waiting.is_null(),
+                "checkpoint a thread blocked in a wait queue"
+            );
+            self.tcb.check_canary();
+            let base = self as *const Self as usize;
+            let mut bytes = vec![0u8; RAW_SIZE].into_boxed_slice();
+            core::ptr::copy_nonoverlapping(base as *const u8, bytes.as_mut_ptr(), RAW_SIZE);
+            StackImage {
+                bytes,
+                base,
+                context: self.tcb.context_ptr as usize - base,
+                _thread: PhantomData,
+            }
+        }
+    }
+
+    /// Restore a thread from `image` in memory provided by the caller.
+    ///
+    /// The saved context holds absolute addresses, so if `at_base` is not
+    /// where the image was taken, every word of the live stack and of the
+    /// saved context which points into the old memory is moved by the
+    /// distance, as are the pointers of the TCB into its own stack. This is
+    /// right for frame pointers and references to locals, and wrong for an
+    /// integer which merely falls in the old range.
+    ///
+    /// The restored thread has no waker until it is polled, and no abort
+    /// handle or pending buffer.
+    ///
+    /// # Safety
+    ///
+    /// - The requirements of [`from_raw`](ThreadFuture::from_raw) on `at_base`.
+    /// - The frames of the thread are copied, not cloned: of the thread and
+    ///   its restored copies, resume at most one, unless its stack owns nothing.
+    /// - The stack must not hold addresses of the old memory in a form other
+    ///   than a plain word, nor integers in its range.
+    pub unsafe fn restore<'a>(image: &StackImage<F, T>, at_base: NonNull<u8>) -> &'a mut Self {
+        let new = at_base.as_ptr() as usize;
+        assert_eq!(new % RAW_SIZE, 0, "misaligned region");
+        core::ptr::copy_nonoverlapping(image.bytes.as_ptr(), new as *mut u8, RAW_SIZE);
+        let relocate = |word: usize| {
+            if (image.base..image.base + RAW_SIZE).contains(&word) {
+                word - image.base + new
+            } else {
+                word
+            }
+        };
+        // the live stack, from the saved context up to the top
+        let word = core::mem::size_of::<usize>();
+        for addr in (new + image.context..new + RAW_SIZE).step_by(word) {
+            let slot = addr as *mut usize;
+            *slot = relocate(*slot);
+        }
+        let tcb = &mut *(new as *mut TCB<F, T>);
+        tcb.context_ptr = relocate(tcb.context_ptr as usize) as _;
+        // the copies are owned by the original thread, so they are not dropped here
+        core::ptr::write(&mut tcb.waker, None);
+        core::ptr::write(&mut tcb.abort, None);
+        #[cfg(feature = "hints")]
+        {
+            core::ptr::write(&mut tcb.hints.buffer, None);
+            tcb.hints.resume_arg = core::ptr::null_mut();
+        }
+        // released when the restored thread is dropped
+        STACK_BYTES.fetch_add(RAW_SIZE, Ordering::Relaxed);
+        &mut *(new as *mut Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::noop_waker;
+    use crate::yield_now;
+    use core::mem::MaybeUninit;
+    use core::task::{Context, Poll};
+
+    #[test]
+    fn round_trip() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| {
+            let mut sum = 0u32;
+            // a reference into the stack, which must be relocated
+            let sum_ref = &mut sum;
+            for i in 1..=4 {
+                *sum_ref += i;
+                yield_now();
+            }
+            sum
+        }));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let image = thread.checkpoint();
+        assert_eq!(image.base(), &*thread as *const _ as usize);
+
+        // the original is never resumed, and stays alive to take another address
+        let mut memory = Box::new(MaybeUninit::<ThreadFuture<fn(), ()>>::uninit());
+        let at_base = NonNull::new(memory.as_mut_ptr() as *mut u8).unwrap();
+        assert_ne!(at_base.as_ptr() as usize, image.base());
+        let restored = unsafe { ThreadFuture::restore(&image, at_base) };
+        let ret = loop {
+            if let Poll::Ready(ret) = restored.poll_unpin(&mut cx) {
+                break ret;
+            }
+        };
+        assert_eq!(ret, 10);
+        unsafe { core::ptr::drop_in_place(restored as *mut _) };
+        drop(thread);
+    }
+}
@@ -0,0 +1,254 @@
+//! Conformance tests which every backend must pass.
+//!
+//! They only use the items listed in `compile_check`, plus `switch_marked`
+//! and `CALLEE_SAVED` from the backend. Run them on the host for x86_64,
+//! and under QEMU for the other architectures:
+//!
+//! ```sh
+//! cross test --lib --target aarch64-unknown-linux-gnu conformance
+//! ```
+
+use super::*;
+
+/// A stack for a bare context, without a TCB.
+#[repr(align(16))]
+struct Stack([u8; 0x1000]);
+
+impl Stack {
+    fn new() -> Box<Self> {
+        Box::new(Stack([0; 0x1000]))
+    }
+
+    /// Build an initial context at the top which enters `pc` with `arg0`.
+    fn context(&mut self, pc: usize, arg0: usize) -> *mut ThreadContext {
+        let top = self.0.as_mut_ptr() as usize + 0x1000;
+        unsafe { ThreadContext::init(top, pc, arg0) }
+    }
+}
+
+/// Registers set by the executor side.
+const EXECUTOR_BASE: usize = 0x1e00_0000;
+/// Registers set by the thread side.
+const THREAD_BASE: usize = 0x7d00_0000;
+
+fn marks(base: usize) -> [usize; CALLEE_SAVED] {
+    let mut marks = [0; CALLEE_SAVED];
+    for (i, mark) in marks.iter_mut().enumerate() {
+        *mark = base + i;
+    }
+    marks
+}
+
+fn switch_addr() -> usize {
+    ThreadContext::switch as usize
+}
+
+#[test]
+fn callee_saved_survive_switch() {
+    static THREAD_OK: AtomicUsize = AtomicUsize::new(0);
+
+    /// Switch back with its own marks forever, counting the rounds
+    /// in which they survived.
+    unsafe extern "C" fn thread(arg: usize) {
+        let ptr_ptr = arg as *mut *mut ThreadContext;
+        let mut out = [0; CALLEE_SAVED];
+        // the first round starts from a fresh context, so there is nothing to check
+        switch_marked(ptr_ptr, out.as_mut_ptr(), THREAD_BASE, switch_addr());
+        loop {
+            if out == marks(THREAD_BASE) {
+                THREAD_OK.fetch_add(1, Ordering::SeqCst);
+            }
+            out = [0; CALLEE_SAVED];
+            switch_marked(ptr_ptr, out.as_mut_ptr(), THREAD_BASE, switch_addr());
+        }
+    }
+
+    let mut stack = Stack::new();
+    let mut context = core::ptr::null_mut();
+    context = stack.context(thread as usize, &mut context as *mut _ as usize);
+    for round in 0..3 {
+        let mut out = [0; CALLEE_SAVED];
+        unsafe {
+            switch_marked(&mut context, out.as_mut_ptr(), EXECUTOR_BASE, switch_addr());
+        }
+        assert_eq!(out, marks(EXECUTOR_BASE), "executor, round {}", round);
+        assert_eq!(THREAD_OK.load(Ordering::SeqCst), round, "thread, round {}", round);
+    }
+}
+
+#[test]
+fn set_entry_jumps_to_target() {
+    static ARRIVED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn target(arg: usize) {
+        ARRIVED.fetch_add(1, Ordering::SeqCst);
+        loop {
+            ThreadContext::switch(arg as *mut *mut ThreadContext);
+            ARRIVED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut stack = Stack::new();
+    let mut context = core::ptr::null_mut();
+    context = stack.context(target as usize, &mut context as *mut _ as usize);
+    let entry = unsafe { read_word(context, CONTEXT_ENTRY_OFFSET) };
+    assert_eq!(entry, target as usize);
+    for round in 1..=2 {
+        unsafe {
+            ThreadContext::switch(&mut context);
+        }
+        assert_eq!(ARRIVED.load(Ordering::SeqCst), round);
+        // the saved context points into the stack of the target
+        let start = stack.0.as_ptr() as usize;
+        assert!((start..start + 0x1000).contains(&(context as usize)));
+    }
+}
+
+#[test]
+fn stack_pointer_is_plausible() {
+    let local = 0u8;
+    let sp = unsafe { stack_pointer() };
+    let addr = &local as *const u8 as usize;
+    // the stack grows down, and the local lives in the current frame
+    assert!(sp <= addr && addr - sp < 0x1000, "sp {:#x}, local {:#x}", sp, addr);
+    assert_eq!(sp % core::mem::size_of::<usize>(), 0);
+
+    let waker = crate::tests::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut thread = Box::new(ThreadFuture::from(|| unsafe { stack_pointer() }));
+    let (base, size) = unsafe { thread.tcb.stack_region() };
+    match thread.poll_unpin(&mut cx) {
+        Poll::Ready(sp) => assert!((base..base + size).contains(&sp)),
+        Poll::Pending => unreachable!(),
+    }
+}
+
+#[test]
+fn poll_stack_usage_matches_switch() {
+    static SAVED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Record where the executor context was saved, then switch back.
+    unsafe extern "C" fn target(arg: usize) {
+        let ptr_ptr = arg as *mut *mut ThreadContext;
+        SAVED.store(*ptr_ptr as usize, Ordering::SeqCst);
+        ThreadContext::switch(ptr_ptr);
+        unreachable!();
+    }
+
+    let mut stack = Stack::new();
+    let mut context = core::ptr::null_mut();
+    context = stack.context(target as usize, &mut context as *mut _ as usize);
+    let sp = unsafe {
+        let sp = stack_pointer();
+        ThreadContext::switch(&mut context);
+        sp
+    };
+    let used = sp - SAVED.load(Ordering::SeqCst);
+    // cdecl pushes the argument, and may align the stack before the call
+    assert!(
+        (poll_stack_usage()..=poll_stack_usage() + 16).contains(&used),
+        "{} bytes used",
+        used
+    );
+}
+
+#[test]
+fn context_size_matches_saved_context() {
+    assert_eq!(context_size(), core::mem::size_of::<ThreadContext>());
+    assert_eq!(context_size(), layout::CONTEXT_SIZE);
+    let waker = crate::tests::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut thread = Box::new(ThreadFuture::from(park));
+    assert!(thread.poll_unpin(&mut cx).is_pending());
+    unsafe {
+        assert_eq!(thread.saved_context_bytes().len(), context_size());
+    }
+}
+
+/// Read a word of a context by its offset in `layout`.
+unsafe fn read_word(context: *const ThreadContext, offset: usize) -> usize {
+    assert!(offset + core::mem::size_of::<usize>() <= layout::CONTEXT_SIZE);
+    *((context as usize + offset) as *const usize)
+}
+
+#[test]
+fn layout_offsets_match_fields() {
+    let mut stack = Stack::new();
+    let context = stack.context(0x1230, 0);
+    unsafe {
+        (*context).set_pc(0x1230);
+        (*context).set_fp(0x4560);
+        assert_eq!(read_word(context, layout::CONTEXT_PC), 0x1230);
+        assert_eq!(read_word(context, layout::CONTEXT_FP), 0x4560);
+    }
+    let offsets: Vec<usize> = layout::CONTEXT_REGISTERS.iter().map(|r| r.1).collect();
+    assert!(offsets.contains(&layout::CONTEXT_PC));
+    assert!(offsets.contains(&layout::CONTEXT_FP));
+
+    let waker = crate::tests::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut thread = Box::new(ThreadFuture::from(park).with_name("layout"));
+    assert!(thread.poll_unpin(&mut cx).is_pending());
+    let base = &*thread as *const _ as usize;
+    unsafe {
+        let context_ptr = *((base + layout::TCB_CONTEXT_PTR) as *const usize);
+        assert_eq!(context_ptr + layout::CONTEXT_SIZE, thread.saved_sp());
+        let bytes = thread.saved_context_bytes();
+        assert_eq!(bytes.len(), layout::CONTEXT_SIZE);
+        assert_eq!(bytes.as_ptr() as usize, context_ptr);
+        let pc = read_word(context_ptr as *const ThreadContext, layout::CONTEXT_PC);
+        assert_eq!(pc, thread.saved_pc());
+
+        let ptr = *((base + layout::TCB_NAME) as *const *const u8);
+        let len = *((base + layout::TCB_NAME_LEN) as *const usize);
+        assert_eq!(core::slice::from_raw_parts(ptr, len), b"layout");
+    }
+}
+
+#[test]
+fn layout_registers_hold_marks() {
+    /// Switch back with its own marks once.
+    unsafe extern "C" fn thread(arg: usize) {
+        let mut out = [0; CALLEE_SAVED];
+        let ptr_ptr = arg as *mut *mut ThreadContext;
+        switch_marked(ptr_ptr, out.as_mut_ptr(), THREAD_BASE, switch_addr());
+        unreachable!();
+    }
+
+    let mut stack = Stack::new();
+    let mut context = core::ptr::null_mut();
+    context = stack.context(thread as usize, &mut context as *mut _ as usize);
+    unsafe {
+        ThreadContext::switch(&mut context);
+    }
+    // every marked register is saved at one of the documented offsets
+    let saved: Vec<usize> = layout::CONTEXT_REGISTERS
+        .iter()
+        .map(|&(_, offset)| unsafe { read_word(context, offset) })
+        .collect();
+    for mark in marks(THREAD_BASE).iter() {
+        assert!(saved.contains(mark), "{:#x} not in {:x?}", mark, saved);
+    }
+}
+
+#[test]
+fn initial_context_is_zeroed() {
+    let mut stack = Stack::new();
+    // stale bytes, as on a reused stack
+    stack.0.iter_mut().for_each(|b| *b = 0xaa);
+    let context = stack.context(0x1230, 0);
+    // the first switch-in loads zero into every register but the PC,
+    // and the one holding the entry if the backend enters by a trampoline
+    for &(name, offset) in layout::CONTEXT_REGISTERS.iter() {
+        let value = unsafe { read_word(context, offset) };
+        if offset == CONTEXT_ENTRY_OFFSET {
+            assert_eq!(value, 0x1230);
+        } else if offset == layout::CONTEXT_PC {
+            assert_ne!(value, 0);
+        } else {
+            assert_eq!(value, 0, "{} is not zeroed", name);
+        }
+    }
+    let reserved = &stack.0[0x1000 - STACK_TOP_RESERVED..];
+    assert!(reserved.iter().all(|&b| b == 0));
+}
@@ -0,0 +1,180 @@
+//! A single-threaded executor with seeded, reproducible scheduling.
+
+use crate::pool::Runnable;
+use crate::ThreadFuture;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+/// An executor which runs green threads on the current OS thread, picking
+/// the next one from the ready queue by a PRNG seeded by the caller.
+///
+/// The same seed and the same threads give the same interleaving, so an
+/// ordering bug found with one seed can be replayed. Trying many seeds
+/// shakes out interleavings a round-robin executor never produces.
+///
+/// Yield hints are ignored, since the pick is random anyway.
+pub struct DeterministicExecutor {
+    /// State of the splitmix64 generator.
+    rng: u64,
+    /// Spawned threads by number. `None` once exited.
+    threads: Vec<Option<Box<dyn Runnable>>>,
+    /// Numbers of woken threads, in the order they are woken.
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+/// The waker of a spawned thread.
+struct Waiter {
+    id: usize,
+    /// Whether the thread is in the ready queue.
+    queued: AtomicBool,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl DeterministicExecutor {
+    /// Create an executor scheduling by `seed`.
+    pub fn new(seed: u64) -> Self {
+        DeterministicExecutor {
+            rng: seed,
+            threads: Vec::new(),
+            ready: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Spawn a thread. Its return value is dropped.
+    ///
+    /// Nothing runs until [`run`](DeterministicExecutor::run).
+    pub fn spawn<F, T>(&mut self, thread: ThreadFuture<F, T>)
+    where
+        F: Send + 'static + Unpin + FnOnce() -> T,
+        T: Send + 'static + Unpin,
+    {
+        let id = self.threads.len();
+        self.threads.push(Some(Box::new(thread)));
+        self.ready.lock().unwrap().push_back(id);
+    }
+
+    /// Run until all spawned threads have exited.
+    ///
+    /// Panics if the threads left are all parked, since nothing else
+    /// could wake them.
+    pub fn run(&mut self) {
+        let wakers: Vec<_> = (0..self.threads.len())
+            .map(|id| {
+                Arc::new(Waiter {
+                    id,
+                    queued: AtomicBool::new(true),
+                    ready: self.ready.clone(),
+                })
+            })
+            .collect();
+        loop {
+            let id = {
+                let mut ready = self.ready.lock().unwrap();
+                if ready.is_empty() {
+                    break;
+                }
+                let index = (self.next_random() % ready.len() as u64) as usize;
+                ready.remove(index).unwrap()
+            };
+            let thread = match self.threads[id].as_mut() {
+                Some(thread) => thread,
+                None => continue,
+            };
+            let waiter = &wakers[id];
+            waiter.queued.store(false, Ordering::Release);
+            let waker = unsafe { Waker::from_raw(raw_waker(waiter.clone())) };
+            let mut cx = Context::from_waker(&waker);
+            if thread.poll(&mut cx).is_ready() {
+                self.threads[id] = None;
+            }
+        }
+        let parked = self.threads.iter().filter(|t| t.is_some()).count();
+        assert!(parked == 0, "deadlock: {} threads are parked", parked);
+        self.threads.clear();
+    }
+
+    /// The next number of splitmix64.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Waiter {
+    /// Put the thread in the ready queue unless it is there.
+    fn wake(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready.lock().unwrap().push_back(self.id);
+        }
+    }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+
+fn raw_waker(waiter: Arc<Waiter>) -> RawWaker {
+    RawWaker::new(Arc::into_raw(waiter) as *const (), &VTABLE)
+}
+
+unsafe fn clone(data: *const ()) -> RawWaker {
+    let waiter = Arc::from_raw(data as *const Waiter);
+    let cloned = waiter.clone();
+    core::mem::forget(waiter);
+    raw_waker(cloned)
+}
+
+unsafe fn wake(data: *const ()) {
+    let waiter = Arc::from_raw(data as *const Waiter);
+    waiter.wake();
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    let waiter = Arc::from_raw(data as *const Waiter);
+    waiter.wake();
+    core::mem::forget(waiter);
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(Arc::from_raw(data as *const Waiter));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yield_now;
+
+    /// Run three threads logging 4 times each, and return the log.
+    fn interleaving(seed: u64) -> Vec<u8> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut executor = DeterministicExecutor::new(seed);
+        for id in 0..3 {
+            let log = log.clone();
+            executor.spawn(ThreadFuture::from(move || {
+                for _ in 0..4 {
+                    log.lock().unwrap().push(id);
+                    yield_now();
+                }
+            }));
+        }
+        executor.run();
+        let log = log.lock().unwrap().clone();
+        log
+    }
+
+    #[test]
+    fn same_seed_same_interleaving() {
+        for seed in 0..8 {
+            let log = interleaving(seed);
+            assert_eq!(log.len(), 12);
+            assert_eq!(log, interleaving(seed));
+        }
+        let logs: Vec<_> = (0..8).map(interleaving).collect();
+        assert!(logs.iter().any(|log| *log != logs[0]));
+    }
+}
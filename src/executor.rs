@@ -0,0 +1,116 @@
+//! A minimal executor running threads on the current stack.
+
+use crate::{LocalThreadFuture, ThreadFuture};
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::null;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// Number of wake-ups of all threads running in [`block_on`].
+///
+/// A waker only bumps it, so it never dangles even if it outlives the thread.
+static WAKE_EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop_waker);
+
+fn clone(_: *const ()) -> RawWaker {
+    RawWaker::new(null(), &VTABLE)
+}
+
+fn wake(_: *const ()) {
+    WAKE_EPOCH.fetch_add(1, Ordering::Release);
+}
+
+fn drop_waker(_: *const ()) {}
+
+/// Run a thread to completion on the current stack.
+///
+/// After the thread calls [`yield_now`](crate::yield_now), it is polled again
+/// at once without relying on the waker. After it [`park`](crate::park)s,
+/// the current CPU spins until any thread running in `block_on` is woken.
+pub fn block_on<F, T>(mut thread: ThreadFuture<F, T>) -> T
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    run(&mut thread)
+}
+
+/// Run a local thread to completion on the current stack, like [`block_on`].
+pub fn block_on_local<F, T>(mut thread: LocalThreadFuture<F, T>) -> T
+where
+    F: 'static + Unpin + FnOnce() -> T,
+    T: 'static + Unpin,
+{
+    run(&mut thread.thread)
+}
+
+fn run<F, T>(thread: &mut ThreadFuture<F, T>) -> T
+where
+    F: 'static + Unpin + FnOnce() -> T,
+    T: 'static + Unpin,
+{
+    let waker = unsafe { Waker::from_raw(clone(null())) };
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        let epoch = WAKE_EPOCH.load(Ordering::Acquire);
+        if let Poll::Ready(ret) = Pin::new(&mut *thread).poll(&mut cx) {
+            return ret;
+        }
+        if thread.is_yielded() {
+            continue;
+        }
+        while WAKE_EPOCH.load(Ordering::Acquire) == epoch {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{current_waker, park, yield_now};
+    use std::time::Duration;
+
+    #[test]
+    fn yield_without_waker() {
+        let ret = block_on(ThreadFuture::from(|| {
+            for _ in 0..3 {
+                yield_now();
+            }
+            1u32
+        }));
+        assert_eq!(ret, 1);
+    }
+
+    #[test]
+    fn local_thread() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let shared = Rc::new(RefCell::new(0));
+        let s = shared.clone();
+        let ret = block_on_local(LocalThreadFuture::from(move || {
+            for _ in 0..3 {
+                *s.borrow_mut() += 1;
+                yield_now();
+            }
+            s
+        }));
+        assert_eq!(*ret.borrow(), 3);
+        assert_eq!(Rc::strong_count(&shared), 2);
+    }
+
+    #[test]
+    fn park_and_wake() {
+        block_on(ThreadFuture::from(|| {
+            let waker = current_waker();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                waker.wake();
+            });
+            park();
+        }));
+    }
+}
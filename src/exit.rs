@@ -0,0 +1,76 @@
+//! Early exit of threads from nested calls.
+
+use core::marker::PhantomData;
+use std::boxed::Box;
+
+/// Payload of the unwind started by [`GreenExit::exit`].
+pub(crate) struct ExitValue<T>(pub T);
+
+/// A token to exit the current thread with a return value of type `T`.
+///
+/// Created by [`current_exit`].
+pub struct GreenExit<T> {
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T> Clone for GreenExit<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for GreenExit<T> {}
+
+/// Get the exit token of the current thread.
+///
+/// `T` must be the return type of the thread, otherwise an exit with it
+/// is resumed as a panic on the executor.
+///
+/// Must be called inside a green thread.
+pub fn current_exit<T: Send + 'static>() -> GreenExit<T> {
+    assert!(crate::in_green_thread(), "not in a green thread");
+    GreenExit {
+        _marker: PhantomData,
+    }
+}
+
+impl<T: Send + 'static> GreenExit<T> {
+    /// Exit the thread with `value` as its return value.
+    ///
+    /// The stack is unwound back to the entry of the thread, running the
+    /// destructors on the way. A `catch_unwind` in between stops it.
+    pub fn exit(self, value: T) -> ! {
+        // skip the panic hook, which needs more stack than a thread has
+        std::panic::resume_unwind(Box::new(ExitValue(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn exit_from_nested_calls() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Guard;
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn nested(depth: u32) -> u32 {
+            let _guard = Guard;
+            if depth == 0 {
+                current_exit::<u32>().exit(42);
+            }
+            nested(depth - 1) + 1
+        }
+
+        let ret = ThreadFuture::from(|| nested(2)).await;
+        assert_eq!(ret, 42);
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 3);
+    }
+}
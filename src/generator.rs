@@ -0,0 +1,71 @@
+//! Threads driving generators of the language.
+
+use crate::{yield_now, ThreadFuture};
+use core::ops::{Generator, GeneratorState};
+use core::pin::Pin;
+
+/// A generator resumed by the closure of a thread.
+///
+/// It is the closure of threads created by
+/// [`from_generator`](ThreadFuture::from_generator).
+pub struct FromGenerator<G> {
+    gen: G,
+}
+
+// the generator is only resumed after it is moved onto the thread stack
+impl<G> Unpin for FromGenerator<G> {}
+
+impl<G, T> FnOnce<()> for FromGenerator<G>
+where
+    G: Generator<Yield = (), Return = T>,
+{
+    type Output = T;
+
+    extern "rust-call" fn call_once(self, _: ()) -> T {
+        let mut gen = self.gen;
+        // `gen` is never moved again
+        let mut gen = unsafe { Pin::new_unchecked(&mut gen) };
+        loop {
+            match gen.as_mut().resume(()) {
+                GeneratorState::Yielded(()) => yield_now(),
+                GeneratorState::Complete(ret) => return ret,
+            }
+        }
+    }
+}
+
+impl<G, T> ThreadFuture<FromGenerator<G>, T>
+where
+    G: Send + 'static + Generator<Yield = (), Return = T>,
+    T: Send + 'static,
+{
+    /// Create a thread resuming `gen` on its stack, which yields to the
+    /// executor at each `yield` of the generator.
+    pub fn from_generator(gen: G) -> Self {
+        ThreadFuture::from(FromGenerator { gen })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::noop_waker;
+    use core::task::{Context, Poll};
+
+    #[test]
+    fn yields_twice() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from_generator(|| {
+            let mut n = 1;
+            yield;
+            n += 1;
+            yield;
+            n + 1
+        }));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(thread.is_yielded());
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(3));
+    }
+}
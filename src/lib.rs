@@ -1,31 +1,252 @@
 //! Convert closures to futures based on greenthread on bare-metal (no_std + no_alloc).
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+#![cfg_attr(all(test, feature = "generators"), feature(generators))]
+#![cfg_attr(feature = "generators", feature(generator_trait))]
 #![feature(asm)]
+#![feature(fn_traits)]
 #![feature(global_asm)]
+#![feature(into_future)]
 #![feature(naked_functions)]
+#![feature(unboxed_closures)]
 #![feature(untagged_unions)]
 #![deny(warnings)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 use core::future::Future;
 use core::mem::ManuallyDrop;
 use core::pin::Pin;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::task::{Context, Poll, Waker};
+use sync::SpinLock;
+
+#[cfg(feature = "std")]
+pub use abort::{AbortHandle, Aborted};
+pub use actor::{actor, AsyncReceiver, AsyncSender, Mailbox, Receiver, Sender};
+#[cfg(feature = "hints")]
+pub use buffer::take_buffer;
+pub use call::{call, Bound, Call};
+#[cfg(feature = "std")]
+pub use checkpoint::StackImage;
+#[cfg(feature = "std")]
+pub use deterministic::DeterministicExecutor;
+pub use executor::{block_on, block_on_local};
+#[cfg(feature = "std")]
+pub use exit::{current_exit, GreenExit};
+#[cfg(feature = "generators")]
+pub use generator::FromGenerator;
+pub use main_thread::{adopt_current_stack, MainThreadHandle};
+#[cfg(feature = "std")]
+pub use nursery::block_in_place;
+pub use nursery::{join_all, spawn_child, ChildHandle, Joinable};
+pub use out_of_band::{out_of_band, out_of_band_in_place};
+pub use out_of_line::out_of_line;
+pub use placed_thread::PlacedThread;
+#[cfg(feature = "std")]
+pub use pool::ThreadPoolExecutor;
+#[cfg(feature = "alloc")]
+pub use stack_alloc::GlobalStack;
+pub use stack_alloc::{BoxedThread, StackAllocator};
+#[cfg(feature = "hints")]
+pub use stream::{green_stream, yield_value, GreenStream};
+pub use sync::{
+    GreenCondvar, GreenMutex, GreenMutexGuard, GreenSemaphore, GreenSemaphorePermit, Rendezvous,
+};
+#[cfg(feature = "hints")]
+pub use tick::yield_tick;
+#[cfg(feature = "tokio")]
+pub use timer::TokioTimer;
+pub use timer::{Timeout, Timer};
+pub use wake_token::{current_wake_token, WakeToken};
+
+#[cfg(feature = "std")]
+mod abort;
+mod actor;
+#[cfg(feature = "hints")]
+mod buffer;
+mod call;
+#[cfg(feature = "std")]
+mod checkpoint;
+#[cfg(test)]
+mod conformance;
+#[cfg(feature = "std")]
+mod deterministic;
+mod executor;
+#[cfg(feature = "std")]
+mod exit;
+#[cfg(feature = "generators")]
+mod generator;
+mod main_thread;
+mod nursery;
+mod out_of_band;
+mod out_of_line;
+mod placed_thread;
+#[cfg(feature = "std")]
+mod pool;
+mod stack_alloc;
+mod static_thread;
+#[cfg(feature = "hints")]
+mod stream;
+mod sync;
+#[cfg(feature = "hints")]
+mod tick;
+mod timer;
+mod wake_token;
+
+#[cfg(not(any(feature = "overflow-canary", feature = "overflow-mpu")))]
+compile_error!("enable at least one of `overflow-canary` and `overflow-mpu`");
 
 #[cfg(target_arch = "x86_64")]
 include!("x86_64.rs");
+#[cfg(target_arch = "x86")]
+include!("x86.rs");
 #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 include!("riscv.rs");
 #[cfg(target_arch = "aarch64")]
 include!("aarch64.rs");
 
 /// Future that wraps a blocking thread.
+///
+/// The closure `F` and the return value `T` are stored in the TCB, so both
+/// must be `Sized`. The bound is written out, and a `?Sized` type is refused
+/// at compile time rather than breaking the layout:
+///
+/// ```compile_fail
+/// use greenthread_future::ThreadFuture;
+///
+/// type Unsized = dyn FnOnce() -> u32 + Send + Unpin;
+/// fn thread(_: ThreadFuture<Unsized, u32>) {}
+/// ```
+///
+/// ```compile_fail
+/// use greenthread_future::ThreadFuture;
+///
+/// fn thread<F: ?Sized + FnOnce() -> u32>(_: &ThreadFuture<F, u32>) {}
+/// ```
+///
+/// # Moving
+///
+/// The type is `Unpin`, but its stack holds frames with addresses into
+/// itself, so it must stay in place from its first poll until it is dropped,
+/// e.g. in a `Box`. Polling a moved thread panics, but nothing catches a moved
+/// thread which is never polled again, and anything outside which points into
+/// its old stack dangles, such as the wait queue node of a thread blocked on
+/// a [`GreenMutex`]. Box the thread before polling it.
 #[repr(C, align(0x2000))]
-pub union ThreadFuture<F, T> {
+pub union ThreadFuture<F: Sized, T: Sized> {
     tcb: ManuallyDrop<TCB<F, T>>,
     stack: [usize; RAW_SIZE / 8],
 }
 
+/// A fire-and-forget thread returning `()`.
+///
+/// The return value slot in its state is zero-sized,
+/// so it costs nothing compared to a thread without one.
+pub type DetachedThread<F> = ThreadFuture<F, ()>;
+
+/// A thread whose closure or return value may be `!Send`.
+///
+/// It allows capturing `Rc` or `RefCell` based state on a single-core system.
+/// The future is never `Send`, so it stays on the OS thread which created it,
+/// e.g. in [`block_on_local`] or a local task set of the runtime.
+///
+/// ```compile_fail
+/// use greenthread_future::LocalThreadFuture;
+///
+/// fn assert_send<T: Send>(_: T) {}
+/// assert_send(LocalThreadFuture::from(|| 1u32));
+/// ```
+#[repr(C)]
+pub struct LocalThreadFuture<F, T> {
+    thread: ThreadFuture<F, T>,
+    _not_send: core::marker::PhantomData<*mut ()>,
+}
+
+impl<F, T> From<F> for LocalThreadFuture<F, T>
+where
+    F: 'static + Unpin + FnOnce() -> T,
+    T: 'static,
+{
+    /// Convert a closure of blocking thread to a local future.
+    fn from(f: F) -> Self {
+        assert_eq!(
+            core::mem::size_of::<ThreadFuture<F, T>>(),
+            RAW_SIZE,
+            "TCB size exceed"
+        );
+        LocalThreadFuture {
+            thread: ThreadFuture {
+                tcb: ManuallyDrop::new(TCB::new(f)),
+            },
+            _not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<F, T> Future for LocalThreadFuture<F, T>
+where
+    F: 'static + Unpin + FnOnce() -> T,
+    T: 'static,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        unsafe { self.map_unchecked_mut(|this| &mut this.thread) }.poll(cx)
+    }
+}
+
+/// How the 0x2000 bytes of a [`ThreadFuture`] are partitioned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackLayout {
+    /// Bytes left for the stack frames of the thread.
+    pub usable_bytes: usize,
+    /// Bytes taken by the TCB at the lowest address.
+    pub tcb_bytes: usize,
+    /// Bytes taken by the initial context at the top of stack,
+    /// including the reserved bytes above it.
+    pub context_bytes: usize,
+}
+
+/// Get the layout of a [`ThreadFuture<F, T>`].
+///
+/// It can be evaluated at compile time to check the headroom of a thread.
+pub const fn stack_layout<F, T>() -> StackLayout {
+    let tcb_bytes = core::mem::size_of::<TCB<F, T>>();
+    let context_bytes = core::mem::size_of::<ThreadContext>() + STACK_TOP_RESERVED;
+    StackLayout {
+        usable_bytes: RAW_SIZE - tcb_bytes - context_bytes,
+        tcb_bytes,
+        context_bytes,
+    }
+}
+
+/// Estimate how many frames of `frame_bytes` fit in a [`ThreadFuture<F, T>`].
+///
+/// Pair it with [`stack_layout`] to assert recursion limits at compile time.
+pub const fn max_frames<F, T>(frame_bytes: usize) -> usize {
+    stack_layout::<F, T>().usable_bytes / frame_bytes
+}
+
+/// Bytes of the executor stack taken by `poll` below its own frame while the thread runs.
+///
+/// It is the registers the switch saves on the executor stack, including the
+/// return address. The frame of `poll` itself depends on the compiler, so size the
+/// executor stack for the deepest `poll` call chain plus this, e.g. on an MCU.
+pub const fn poll_stack_usage() -> usize {
+    core::mem::size_of::<ThreadContext>()
+}
+
+/// Bytes of the saved context of a parked thread on this architecture.
+///
+/// Together with [`layout`], it tells tooling which bytes to copy to capture
+/// the saved registers, e.g. for checkpointing. It is [`layout::CONTEXT_SIZE`].
+pub const fn context_size() -> usize {
+    core::mem::size_of::<ThreadContext>()
+}
+
 /// Thread Control Block (TCB)
 ///
 /// This struct is allocated on heap whose start address is aligned to 0x2000.
@@ -35,39 +256,700 @@ struct TCB<F, T> {
     /// Pointer to the context of executor or thread.
     ///
     /// Running thread call `switch` on this to switch back to executor.
+    /// It is whoever polled the thread last, so a thread polled by another
+    /// green thread switches back to that thread, not to the outer executor.
     context_ptr: *mut ThreadContext,
 
+    /// The name of thread, right after `context_ptr` for debuggers.
+    name: ThreadName,
+
     /// The waker of task.
     waker: Option<Waker>,
 
-    /// A canary value to detect stack overflow.
-    canary: usize,
+    /// Another canary, below the fields above and after those read by a debugger.
+    ///
+    /// An overflow hits the top canary first, but a frame which skips a few
+    /// words, e.g. a large array written sparsely, may clobber the fields in
+    /// between without touching it. This one still guards the context
+    /// pointer and the waker then.
+    #[cfg(feature = "double-canary")]
+    inner_canary: usize,
+
+    /// A function run on the thread stack before the closure.
+    prologue: Option<fn()>,
+
+    /// Thread local slots.
+    tls: [usize; GREEN_TLS_SLOTS],
+
+    /// Number of yields the thread may make in one poll.
+    yield_budget: usize,
+
+    /// Yields left before switching back to the executor in this poll.
+    budget_left: usize,
+
+    /// The wait queue node the thread is blocked on, to unlink it if the
+    /// thread is dropped.
+    waiting: *mut sync::WaitLink,
+
+    /// Optional registers saved while the thread is not running.
+    ext: ExtContext,
+
+    /// Hints for the executor.
+    hints: Hints,
+
+    /// Whether the thread is running on CPU now.
+    on_cpu: bool,
+
+    /// Whether to fill the stack with `STACK_POISON` before the first run.
+    poison: bool,
+
+    /// Whether `current()` skips the canary check, in `without_canary_check`.
+    skip_canary: bool,
+
+    /// Register groups preserved across switches.
+    regs: RegSet,
+
+    /// Bytes to lower the initial stack pointer by, to spread hot frames of
+    /// threads over different cache sets. `None` if no color is given.
+    color: Option<u16>,
+
+    /// Offset of `canary` from the start of TCB.
+    ///
+    /// It depends on `F` and `T`, so it is recorded for the type-erased `current()`.
+    #[cfg(feature = "overflow-canary")]
+    canary_offset: usize,
+
+    /// State shared with the abort handles, if any.
+    #[cfg(feature = "std")]
+    abort: Option<std::sync::Arc<abort::AbortState>>,
 
     /// Thread state. Contains function object or return value.
     state: State<F, T>,
+
+    /// A canary value to detect stack overflow.
+    ///
+    /// It is the last field, next to the stack, so an overflow hits it
+    /// before clobbering `context_ptr` or `waker`.
+    #[cfg(feature = "overflow-canary")]
+    canary: usize,
+}
+
+// the context and waker are only touched by the owner of the TCB
+unsafe impl<F: Send, T: Send> Send for TCB<F, T> {}
+
+/// The name of a thread, as a pointer and a length.
+///
+/// It is `#[repr(C)]` rather than an `Option<&str>`, whose layout is up to
+/// the compiler, so that a debugger can read it at [`layout::TCB_NAME`].
+/// The pointer is null if the thread is unnamed.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ThreadName {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl ThreadName {
+    const NONE: Self = ThreadName {
+        ptr: core::ptr::null(),
+        len: 0,
+    };
+
+    fn new(name: &'static str) -> Self {
+        ThreadName {
+            ptr: name.as_ptr(),
+            len: name.len(),
+        }
+    }
+
+    fn get(self) -> Option<&'static str> {
+        if self.ptr.is_null() {
+            return None;
+        }
+        // it is only built from a `&'static str`
+        unsafe {
+            let bytes = core::slice::from_raw_parts(self.ptr, self.len);
+            Some(core::str::from_utf8_unchecked(bytes))
+        }
+    }
+}
+
+/// Hints and hand-over slots which the crate only stores for the executor.
+///
+/// They take stack from every thread, so all but `yielded`, which `poll`
+/// needs itself, are only kept with the `hints` feature. Without it the
+/// hints read as their defaults and setting them does nothing.
+#[repr(C)]
+struct Hints {
+    /// The deadline in ticks of a clock known to the executor.
+    #[cfg(feature = "hints")]
+    deadline: Option<u64>,
+
+    /// The core the thread must run on, given by `set_affinity`.
+    #[cfg(feature = "hints")]
+    affinity: Option<usize>,
+
+    /// The buffer handed over by `put_buffer` and not taken yet.
+    #[cfg(feature = "hints")]
+    buffer: Option<NonNull<[u8]>>,
+
+    /// Pointer to the `ResumeArg` given by `resume_with` during a poll.
+    #[cfg(feature = "hints")]
+    resume_arg: *mut (),
+
+    /// The priority hint given at the last yield.
+    #[cfg(feature = "hints")]
+    priority: Priority,
+
+    /// The enqueue position hint given at the last yield.
+    #[cfg(feature = "hints")]
+    enqueue: Enqueue,
+
+    /// The expected run duration given by `hint_duration`.
+    #[cfg(feature = "hints")]
+    run_class: RunClass,
+
+    /// Why the thread switched out last.
+    #[cfg(feature = "hints")]
+    reason: YieldReason,
+
+    /// Whether the thread switched out by `yield_now` rather than `park`.
+    yielded: bool,
 }
 
-unsafe impl<F, T> Send for TCB<F, T> {}
+impl Hints {
+    /// Hints of a new thread.
+    const fn new() -> Self {
+        Hints {
+            #[cfg(feature = "hints")]
+            deadline: None,
+            #[cfg(feature = "hints")]
+            affinity: None,
+            #[cfg(feature = "hints")]
+            buffer: None,
+            #[cfg(feature = "hints")]
+            resume_arg: core::ptr::null_mut(),
+            #[cfg(feature = "hints")]
+            priority: Priority::Normal,
+            #[cfg(feature = "hints")]
+            enqueue: Enqueue::Back,
+            #[cfg(feature = "hints")]
+            run_class: RunClass::Medium,
+            #[cfg(feature = "hints")]
+            reason: YieldReason::Voluntary,
+            yielded: false,
+        }
+    }
+
+    /// Record a switch out by a yield.
+    #[cfg_attr(not(feature = "hints"), allow(unused_variables))]
+    fn record_yield(&mut self, priority: Priority, enqueue: Enqueue, reason: YieldReason) {
+        #[cfg(feature = "hints")]
+        {
+            self.priority = priority;
+            self.enqueue = enqueue;
+            self.reason = reason;
+        }
+        self.yielded = true;
+    }
+
+    /// Record a switch out by `park`.
+    fn record_park(&mut self) {
+        #[cfg(feature = "hints")]
+        {
+            self.reason = YieldReason::Wait;
+        }
+        self.yielded = false;
+    }
+
+    /// Get the priority hint given at the last yield.
+    fn priority(&self) -> Priority {
+        #[cfg(feature = "hints")]
+        {
+            self.priority
+        }
+        #[cfg(not(feature = "hints"))]
+        {
+            Priority::Normal
+        }
+    }
+
+    /// Get the enqueue position hint given at the last yield.
+    fn enqueue(&self) -> Enqueue {
+        #[cfg(feature = "hints")]
+        {
+            self.enqueue
+        }
+        #[cfg(not(feature = "hints"))]
+        {
+            Enqueue::Back
+        }
+    }
+
+    /// Get why the thread switched out last.
+    fn reason(&self) -> YieldReason {
+        #[cfg(feature = "hints")]
+        {
+            self.reason
+        }
+        #[cfg(not(feature = "hints"))]
+        {
+            if self.yielded {
+                YieldReason::Voluntary
+            } else {
+                YieldReason::Wait
+            }
+        }
+    }
+
+    /// Get the expected run duration.
+    fn run_class(&self) -> RunClass {
+        #[cfg(feature = "hints")]
+        {
+            self.run_class
+        }
+        #[cfg(not(feature = "hints"))]
+        {
+            RunClass::Medium
+        }
+    }
+
+    /// Set the expected run duration.
+    #[cfg_attr(not(feature = "hints"), allow(unused_variables))]
+    fn set_run_class(&mut self, class: RunClass) {
+        #[cfg(feature = "hints")]
+        {
+            self.run_class = class;
+        }
+    }
+
+    /// Get the deadline.
+    fn deadline(&self) -> Option<u64> {
+        #[cfg(feature = "hints")]
+        {
+            self.deadline
+        }
+        #[cfg(not(feature = "hints"))]
+        {
+            None
+        }
+    }
+
+    /// Set the deadline.
+    #[cfg_attr(not(feature = "hints"), allow(unused_variables))]
+    fn set_deadline(&mut self, deadline: Option<u64>) {
+        #[cfg(feature = "hints")]
+        {
+            self.deadline = deadline;
+        }
+    }
+
+    /// Get the core the thread must run on.
+    fn affinity(&self) -> Option<usize> {
+        #[cfg(feature = "hints")]
+        {
+            self.affinity
+        }
+        #[cfg(not(feature = "hints"))]
+        {
+            None
+        }
+    }
+
+    /// Pin the thread to the core numbered `core`.
+    #[cfg_attr(not(feature = "hints"), allow(unused_variables))]
+    fn set_affinity(&mut self, core: usize) {
+        #[cfg(feature = "hints")]
+        {
+            self.affinity = Some(core);
+        }
+    }
+}
 
 const RAW_SIZE: usize = 0x2000;
 
-#[cfg(target_pointer_width = "32")]
+/// The unit of stack coloring.
+const CACHE_LINE: usize = 64;
+
+/// The most bytes a stack color can take.
+const MAX_STACK_COLOR: usize = RAW_SIZE / 4;
+
+/// The byte filling a poisoned stack.
+const STACK_POISON: u8 = 0xaa;
+
+#[cfg(all(feature = "overflow-canary", target_pointer_width = "32"))]
 const CANARY: usize = 0xdeadbeaf;
-#[cfg(target_pointer_width = "64")]
+#[cfg(all(feature = "overflow-canary", target_pointer_width = "64"))]
 const CANARY: usize = 0xcafebabe_deadbeaf;
 
 impl<F, T> TCB<F, T> {
+    /// Create a TCB for a thread which will run `f`.
+    fn new(f: F) -> Self {
+        // released when the `ThreadFuture` holding it is dropped
+        STACK_BYTES.fetch_add(RAW_SIZE, Ordering::Relaxed);
+        Self::unaccounted(f)
+    }
+
+    /// `new` without adding the thread to [`total_stack_bytes`], for callers
+    /// which have accounted it already.
+    fn unaccounted(f: F) -> Self {
+        #[cfg_attr(not(feature = "overflow-canary"), allow(unused_mut))]
+        let mut tcb = TCB {
+            context_ptr: core::ptr::null_mut(),
+            name: ThreadName::NONE,
+            waker: None,
+            #[cfg(feature = "double-canary")]
+            inner_canary: CANARY,
+            prologue: None,
+            tls: [0; GREEN_TLS_SLOTS],
+            yield_budget: 1,
+            budget_left: 0,
+            waiting: core::ptr::null_mut(),
+            ext: ExtContext::default(),
+            hints: Hints::new(),
+            on_cpu: false,
+            poison: false,
+            skip_canary: false,
+            regs: RegSet::DEFAULT,
+            color: None,
+            #[cfg(feature = "overflow-canary")]
+            canary_offset: 0,
+            #[cfg(feature = "std")]
+            abort: None,
+            state: State::Ready(f),
+            #[cfg(feature = "overflow-canary")]
+            canary: CANARY,
+        };
+        #[cfg(feature = "overflow-canary")]
+        {
+            tcb.canary_offset =
+                &tcb.canary as *const usize as usize - &tcb as *const Self as usize;
+        }
+        tcb
+    }
+
+    /// Get the usable stack region `(base, size)` of the thread owning this TCB.
+    fn stack_region(&self) -> (usize, usize) {
+        let tcb_size = core::mem::size_of::<Self>();
+        (self as *const Self as usize + tcb_size, RAW_SIZE - tcb_size)
+    }
+
     /// Get a mutable reference of current TCB.
     unsafe fn current() -> &'static mut Self {
-        let sp = stack_pointer() & !(RAW_SIZE - 1);
-        let tcb = &mut *(sp as *mut Self);
+        let tcb = Self::current_unchecked();
         // ensure we got a valid structure
-        assert_eq!(
-            tcb.canary, CANARY,
-            "canary is changed. maybe stack overflow!"
-        );
+        if !tcb.skip_canary {
+            tcb.check_canary();
+        }
+        tcb
+    }
+
+    /// Get a mutable reference of current TCB without checking the canary.
+    ///
+    /// # Safety
+    ///
+    /// It must be called on the stack of a running green thread, and the
+    /// stack must not have overflowed. Otherwise the reference points to
+    /// garbage, or to a TCB clobbered by the overflow, and nothing catches it.
+    unsafe fn current_unchecked() -> &'static mut Self {
+        let sp = stack_pointer() & !(RAW_SIZE - 1);
+        &mut *(sp as *mut Self)
+    }
+
+    /// Get current TCB on hot paths.
+    ///
+    /// Release builds only compare the top canary, which also rejects a stack
+    /// which is not a green thread at all. Debug builds check all canaries.
+    unsafe fn current_hot() -> &'static mut Self {
+        if cfg!(debug_assertions) {
+            return Self::current();
+        }
+        let tcb = Self::current_unchecked();
+        #[cfg(feature = "overflow-canary")]
+        {
+            let valid = tcb.canary_offset < RAW_SIZE && *tcb.canary() == CANARY;
+            if !valid && !tcb.skip_canary {
+                // reports and panics
+                tcb.check_canary();
+            }
+        }
         tcb
     }
+
+    /// Check canaries of the thread, if any. Panic if any of them is changed.
+    ///
+    /// Without the `overflow-canary` feature, overflows are caught by the MPU
+    /// or a guard page instead, and it does nothing.
+    fn check_canary(&self) {
+        #[cfg(feature = "overflow-canary")]
+        {
+            // a garbage offset means this is not a TCB at all
+            assert!(
+                self.canary_offset < RAW_SIZE,
+                "canary is changed. maybe stack overflow!"
+            );
+            let canary = self.canary();
+            if *canary != CANARY {
+                self.report_overflow(canary);
+            }
+            assert_eq!(
+                *canary, CANARY,
+                "canary is changed. maybe stack overflow! thread: {:?}",
+                self.name.get()
+            );
+            #[cfg(feature = "double-canary")]
+            {
+                if self.inner_canary != CANARY {
+                    self.report_overflow(&self.inner_canary);
+                }
+                assert_eq!(
+                    self.inner_canary, CANARY,
+                    "inner canary is changed. maybe stack overflow! thread: {:?}",
+                    self.name.get()
+                );
+            }
+        }
+    }
+
+    /// Unwind the current thread if it has been aborted.
+    #[cfg(feature = "std")]
+    fn check_abort(&self) {
+        if let Some(abort) = &self.abort {
+            abort.check();
+        }
+    }
+
+    /// Get the top canary by its recorded offset.
+    #[cfg(feature = "overflow-canary")]
+    fn canary(&self) -> &usize {
+        unsafe { &*((self as *const Self as usize + self.canary_offset) as *const usize) }
+    }
+
+    /// Report a changed canary to the overflow logger if any,
+    /// then hand it over to the fault handler if any.
+    #[cfg(feature = "overflow-canary")]
+    fn report_overflow(&self, canary: &usize) {
+        let (stack_base, stack_size) = self.stack_region();
+        let info = StackOverflowInfo {
+            name: self.name.get(),
+            stack_base,
+            stack_size,
+            canary_addr: canary as *const usize as usize,
+            expected: CANARY,
+            found: *canary,
+        };
+        #[cfg(feature = "defmt")]
+        defmt::error!("stack overflow: {}", info);
+        let logger = *OVERFLOW_LOGGER.lock();
+        if let Some(logger) = logger {
+            logger.report(&info);
+        }
+        let handler = STACK_FAULT_HANDLER.load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: fn(StackOverflowInfo) -> ! = unsafe { core::mem::transmute(handler) };
+            handler(info);
+        }
+    }
+}
+
+impl ThreadContext {
+    /// `switch`, counted in [`total_context_switches`] with the `metrics` feature.
+    #[inline(always)]
+    unsafe fn switch_counted(ptr_ptr: *mut *mut Self) {
+        #[cfg(feature = "metrics")]
+        TOTAL_SWITCHES.fetch_add(1, Ordering::Relaxed);
+        Self::switch(ptr_ptr);
+    }
+
+    /// `switch_counted` with the register groups in `regs` swapped with `ext`.
+    #[inline(always)]
+    unsafe fn switch_ext_counted(ptr_ptr: *mut *mut Self, ext: *mut ExtContext, regs: RegSet) {
+        #[cfg(feature = "metrics")]
+        TOTAL_SWITCHES.fetch_add(1, Ordering::Relaxed);
+        Self::switch_ext(ptr_ptr, ext, regs.0 as usize, Self::switch as usize);
+    }
+
+    /// Build the initial context of a new stack ending at `top`, which enters
+    /// `pc` with `arg0` in the first argument register at the first switch.
+    ///
+    /// The context and the bytes reserved above it are zeroed, so every
+    /// register the first switch loads has a defined value rather than stale
+    /// stack contents, and the frame chain ends at `pc`. It costs a few stores
+    /// per thread, once. `switch` itself never saves the argument register;
+    /// the backend passes `arg0` through the initial context only.
+    ///
+    /// # Safety
+    ///
+    /// The `size_of::<ThreadContext>() + STACK_TOP_RESERVED` bytes below `top`
+    /// must be writable, and `top` must be aligned as the ABI requires.
+    unsafe fn init(top: usize, pc: usize, arg0: usize) -> *mut Self {
+        let context = ((top - STACK_TOP_RESERVED) as *mut Self).sub(1);
+        core::ptr::write_bytes(
+            context as *mut u8,
+            0,
+            core::mem::size_of::<Self>() + STACK_TOP_RESERVED,
+        );
+        (*context).set_entry(pc, arg0);
+        // terminate the frame chain for backtraces
+        (*context).set_fp(0);
+        context
+    }
+
+    /// Get the context at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a saved context, which lives for `'a`.
+    unsafe fn from_ptr<'a>(ptr: usize) -> &'a Self {
+        &*(ptr as *const Self)
+    }
+
+    /// Get the raw bytes of the context.
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// Priority hint given by a yielding thread.
+///
+/// Executors may use it to reorder their ready queue, or simply ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Run other threads first.
+    Low,
+    /// No preference.
+    Normal,
+    /// Run me again as soon as possible.
+    High,
+}
+
+/// Hint of where a yielding thread should be put in the ready queue.
+///
+/// The built-in [`ThreadPoolExecutor`](crate::ThreadPoolExecutor) honors it.
+/// Other executors may ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enqueue {
+    /// At the back, taking turns with other threads.
+    Back,
+    /// At the front, running again before other threads.
+    Front,
+}
+
+/// Groups of registers preserved across the switches of a thread.
+///
+/// `INT`, the integer callee-saved registers, is always preserved by the switch.
+/// The other groups are saved and restored around it only if they are in the set
+/// given by [`ThreadFuture::with_registers`], which saves time for threads which
+/// do not use them. Such a thread must not change the registers of a group left
+/// out, or the executor sees the change. What each group holds depends on the
+/// architecture, and may be nothing when the ABI has no callee-saved state there.
+///
+/// `TLS` is not in the default set. Restoring it gives a thread back the
+/// thread pointer of the OS thread which polled it last, so after it moves to
+/// another OS thread, e.g. by work stealing, every `thread_local!` access reads
+/// the TLS of the old one. Only add it for threads which always run on one OS
+/// thread and switch the thread pointer themselves, e.g. in a kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegSet(u8);
+
+impl RegSet {
+    /// Integer callee-saved registers, including the stack and frame pointer.
+    pub const INT: RegSet = RegSet(1);
+    /// Floating point registers and their control state.
+    pub const FP: RegSet = RegSet(2);
+    /// The thread pointer. Incompatible with executors which move threads
+    /// between OS threads, such as the `ThreadPoolExecutor`.
+    pub const TLS: RegSet = RegSet(4);
+    /// Vector registers and their control state.
+    pub const VECTOR: RegSet = RegSet(8);
+    /// All groups but `TLS`. It is the default.
+    pub const DEFAULT: RegSet = RegSet(0xb);
+    /// All groups.
+    pub const ALL: RegSet = RegSet(0xf);
+
+    /// Whether all groups in `other` are in the set.
+    pub fn contains(self, other: RegSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any group in `other` is in the set.
+    pub fn intersects(self, other: RegSet) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl core::ops::BitOr for RegSet {
+    type Output = RegSet;
+
+    fn bitor(self, rhs: RegSet) -> RegSet {
+        RegSet(self.0 | rhs.0)
+    }
+}
+
+/// Identifier of a thread, given by [`ThreadFuture::id`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ThreadId(usize);
+
+/// How a thread switched back to the executor, as seen by the post-yield hook.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldKind {
+    /// It yielded and is ready to run again.
+    Yield,
+    /// It parked until woken.
+    Park,
+    /// It exited or panicked.
+    Exit,
+}
+
+/// The result of [`ThreadFuture::poll_detailed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetailedPoll<T> {
+    /// It exited with the value.
+    Ready(T),
+    /// It yielded and is ready to run again.
+    Yielded,
+    /// It parked until woken.
+    Parked,
+}
+
+/// Why a thread switched out, for tracing and dumps.
+///
+/// It is given by [`yield_with_reason`], and seen by the post-yield hook.
+/// [`yield_now`] gives `Voluntary` and [`park`] gives `Wait`.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldReason {
+    /// It has nothing better to do, or lets others run.
+    Voluntary,
+    /// It used up its time slice, e.g. in a cooperative preemption point.
+    Preempted,
+    /// It waits for I/O.
+    Io,
+    /// It waits for a lock.
+    Lock,
+    /// It waits to be woken.
+    Wait,
+}
+
+/// How long a thread expects to run before its next yield.
+///
+/// Executors may use it for QoS policies, e.g. to run `Short` threads first,
+/// or simply ignore it. The built-in executors ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunClass {
+    /// Runs briefly before yielding.
+    Short,
+    /// No preference.
+    Medium,
+    /// Holds the CPU a while before yielding.
+    Long,
 }
 
 /// Thread state
@@ -75,10 +957,26 @@ enum State<F, T> {
     Ready(F),
     Running,
     Exited(T),
+    /// The closure unwound with the payload.
+    #[cfg(feature = "std")]
+    Panicked(std::boxed::Box<dyn core::any::Any + Send>),
+    /// The panic has been resumed on the executor.
+    #[cfg(feature = "std")]
+    Unwound,
     Invalid,
 }
 
 impl<F, T> State<F, T> {
+    /// Whether the thread has finished and its result has been taken.
+    fn is_finished(&self) -> bool {
+        match self {
+            State::Invalid => true,
+            #[cfg(feature = "std")]
+            State::Unwound => true,
+            _ => false,
+        }
+    }
+
     /// Takes the return value out of the state if it's `Exited`.
     fn take_ret(&mut self) -> Option<T> {
         if let State::Exited(_) = self {
@@ -96,141 +994,2624 @@ impl<F, T> State<F, T> {
 impl<F, T> From<F> for ThreadFuture<F, T>
 where
     F: Send + 'static + Unpin + FnOnce() -> T,
-    T: Send + 'static + Unpin,
+    T: Send + 'static,
 {
     /// Convert a closure of blocking thread to future.
     ///
+    /// If `T` is `!Unpin`, so is the future, and it must be pinned before polling.
+    ///
     /// # Example
     /// TODO
     fn from(f: F) -> Self {
         assert_eq!(core::mem::size_of::<Self>(), RAW_SIZE, "TCB size exceed");
         ThreadFuture {
-            tcb: ManuallyDrop::new(TCB {
-                context_ptr: core::ptr::null_mut(),
-                waker: None,
-                canary: CANARY,
-                state: State::Ready(f),
-            }),
+            tcb: ManuallyDrop::new(TCB::new(f)),
         }
     }
 }
 
-impl<F, T> Future for ThreadFuture<F, T>
+/// A closure built by its builder when the thread first runs.
+///
+/// It is the closure of threads created by
+/// [`with_on_first_poll`](ThreadFuture::with_on_first_poll).
+pub struct OnFirstPoll<B> {
+    builder: B,
+}
+
+impl<B, G, T> FnOnce<()> for OnFirstPoll<B>
 where
-    F: Send + 'static + Unpin + FnOnce() -> T,
-    T: Send + 'static + Unpin,
+    B: FnOnce() -> G,
+    G: FnOnce() -> T,
 {
     type Output = T;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        // allocate executor context at stack
-        let raw = self.get_mut();
-        let state = unsafe {
-            // fill SP and PC at first run
+    extern "rust-call" fn call_once(self, _: ()) -> T {
+        (self.builder)()()
+    }
+}
+
+impl<B, G, T> ThreadFuture<OnFirstPoll<B>, T>
+where
+    B: Send + 'static + Unpin + FnOnce() -> G,
+    G: FnOnce() -> T,
+    T: Send + 'static,
+{
+    /// Create a thread whose closure is built by `builder` at the first poll.
+    ///
+    /// The cost of building the closure and its captures is not paid for
+    /// threads dropped before they run, e.g. cancelled in a long queue.
+    /// The closure is built on the thread stack, after the entry prologue,
+    /// so only `builder` takes room in the TCB.
+    pub fn with_on_first_poll(builder: B) -> Self {
+        ThreadFuture::from(OnFirstPoll { builder })
+    }
+}
+
+impl<F, T> ThreadFuture<F, T> {
+    /// Set the name of the thread.
+    ///
+    /// It should be called before the first poll.
+    /// The name can be read inside the thread by [`current_thread_name`],
+    /// and by a debugger at [`layout::TCB_NAME`] of the thread memory.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        unsafe {
+            (*self.tcb).name = ThreadName::new(name);
+        }
+        self
+    }
+
+    /// Get the name of the thread set by [`with_name`](Self::with_name).
+    pub fn name(&self) -> Option<&'static str> {
+        unsafe { self.tcb.name.get() }
+    }
+
+    /// Set a function to run on the thread before the closure.
+    ///
+    /// It should be called before the first poll.
+    /// The prologue runs exactly once, on the stack of the thread,
+    /// e.g. to mask interrupts or set up per-thread CPU state.
+    pub fn with_entry_prologue(mut self, prologue: fn()) -> Self {
+        unsafe {
+            (*self.tcb).prologue = Some(prologue);
+        }
+        self
+    }
+
+    /// Let the thread yield up to `budget` times in one poll.
+    ///
+    /// Each yield takes one unit of the budget. While some is left, the yield
+    /// returns at once without switching, and the executor gets the control
+    /// back on the yield which uses up the budget, or on `park` or exit.
+    /// The budget is refilled at every poll. It is 1 by default, so every yield
+    /// switches out. A larger one saves switches for threads which yield often,
+    /// while a rarely yielding thread still hands back the CPU at its first yield.
+    pub fn with_yield_budget(mut self, budget: usize) -> Self {
+        assert!(budget > 0, "zero yield budget");
+        unsafe {
+            (*self.tcb).yield_budget = budget;
+        }
+        self
+    }
+
+    /// Set the register groups preserved across switches. `INT` is always added.
+    ///
+    /// It should be called before the first poll. See [`RegSet`].
+    ///
+    /// # Safety
+    ///
+    /// Some groups hold registers which are callee-saved by the ABI, e.g.
+    /// d8-d15 in `FP` on aarch64 and fs0-fs11 on riscv, and compiled code on
+    /// both sides of the switch relies on them. A group may only be left out
+    /// if neither the thread nor the executor can observe its registers
+    /// changing across a switch, e.g. the thread runs no floating point code.
+    /// `TLS` must only be added under the conditions given by [`RegSet`].
+    pub unsafe fn with_registers(mut self, regs: RegSet) -> Self {
+        (*self.tcb).regs = regs | RegSet::INT;
+        self
+    }
+
+    /// Fill the stack with a pattern before the first run,
+    /// so that [`stack_used_bytes`](Self::stack_used_bytes) can measure it.
+    ///
+    /// It should be called before the first poll.
+    /// Filling costs a write of the whole stack when the thread starts,
+    /// but nothing while it runs.
+    pub fn with_stack_poison(mut self) -> Self {
+        unsafe {
+            (*self.tcb).poison = true;
+        }
+        self
+    }
+
+    /// Lower the initial stack pointer of the thread by `lines` cache lines.
+    ///
+    /// All threads are aligned to 0x2000, so their hot frames near the top of
+    /// stack map to the same cache sets, and evict each other in switch-heavy
+    /// workloads. Giving threads different colors spreads them over more sets.
+    /// The TCB stays in place. The price is `lines * 64` bytes of stack.
+    ///
+    /// It should be called before the first poll.
+    /// The [`ThreadPoolExecutor`](crate::ThreadPoolExecutor) can assign colors
+    /// round-robin by `with_stack_coloring`.
+    pub fn with_stack_color(mut self, lines: usize) -> Self {
+        self.set_stack_color(lines);
+        self
+    }
+
+    fn set_stack_color(&mut self, lines: usize) {
+        let bytes = lines
+            .checked_mul(CACHE_LINE)
+            .filter(|&bytes| bytes <= MAX_STACK_COLOR)
+            .expect("stack color too large");
+        unsafe {
+            (*self.tcb).color = Some(bytes as u16);
+        }
+    }
+
+    /// Get the most bytes of stack the thread has used so far.
+    ///
+    /// The stack is scanned from the bottom for the first byte changed from
+    /// the pattern, so a frame which happened to write the pattern byte is
+    /// not counted. Returns `None` if the stack is not poisoned
+    /// or the thread has not been polled.
+    pub fn stack_used_bytes(&self) -> Option<usize> {
+        let tcb = unsafe { &*self.tcb };
+        if !tcb.poison || tcb.context_ptr.is_null() {
+            return None;
+        }
+        let (base, size) = tcb.stack_region();
+        let untouched = (base..base + size)
+            .take_while(|&addr| unsafe { *(addr as *const u8) } == STACK_POISON)
+            .count();
+        Some(size - untouched)
+    }
+
+    /// Check canaries of the thread. Panic if stack overflow is detected.
+    pub fn check_stack(&self) {
+        unsafe { self.tcb.check_canary() }
+    }
+
+    /// Get the priority hint given by the thread at its last yield.
+    pub fn priority(&self) -> Priority {
+        unsafe { self.tcb.hints.priority() }
+    }
+
+    /// Get why the thread switched out last.
+    pub fn yield_reason(&self) -> YieldReason {
+        unsafe { self.tcb.hints.reason() }
+    }
+
+    /// Get a handle to abort the thread from another task.
+    #[cfg(feature = "std")]
+    pub fn abort_handle(&mut self) -> AbortHandle {
+        let tcb = unsafe { &mut *self.tcb };
+        let waker = tcb.waker.clone();
+        let state = tcb
+            .abort
+            .get_or_insert_with(|| std::sync::Arc::new(abort::AbortState::new(waker)));
+        AbortHandle {
+            state: state.clone(),
+        }
+    }
+
+    /// Whether the thread switched out by a yield at the last poll,
+    /// rather than by [`park`].
+    ///
+    /// Both return `Poll::Pending`, but mean different things to an executor.
+    /// A yielded thread has woken itself and is ready to run again, so the
+    /// executor may poll it again at once instead of going through its queue.
+    /// A parked thread must not be polled until someone wakes it, since it
+    /// would only park again.
+    pub fn is_yielded(&self) -> bool {
+        unsafe { self.tcb.hints.yielded }
+    }
+
+    /// Whether the closure panicked.
+    ///
+    /// The panic is resumed on the executor by the poll which saw it.
+    /// By then the stack has been unwound, so the thread can be dropped
+    /// or [`reset`](ThreadFuture::reset) safely. Always false without `std`,
+    /// where a panic aborts.
+    pub fn was_panicked(&self) -> bool {
+        #[cfg(feature = "std")]
+        {
+            if let State::Panicked(_) | State::Unwound = unsafe { &self.tcb.state } {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Get the identifier of the thread, which is the address of its memory.
+    ///
+    /// It is unique among living threads, and may be reused after one is dropped.
+    pub fn id(&self) -> ThreadId {
+        ThreadId(self as *const Self as usize)
+    }
+
+    /// Get the enqueue position hint given by the thread at its last yield.
+    pub fn enqueue_hint(&self) -> Enqueue {
+        unsafe { self.tcb.hints.enqueue() }
+    }
+
+    /// Set the deadline of the thread, in ticks of a clock known to the executor.
+    ///
+    /// The thread can change it later by [`set_deadline`].
+    pub fn with_deadline(mut self, deadline: u64) -> Self {
+        unsafe {
+            (*self.tcb).hints.set_deadline(Some(deadline));
+        }
+        self
+    }
+
+    /// Get the deadline of the thread.
+    ///
+    /// An EDF executor may order its ready queue by it.
+    /// The crate knows no clock, so it only stores the value.
+    pub fn deadline(&self) -> Option<u64> {
+        unsafe { self.tcb.hints.deadline() }
+    }
+
+    /// Get the run duration hint given by the thread by [`hint_duration`].
+    pub fn run_class(&self) -> RunClass {
+        unsafe { self.tcb.hints.run_class() }
+    }
+
+    /// Pin the thread to the core numbered `core`, for threads which touch
+    /// core-local state such as a per-core device.
+    ///
+    /// The crate does not schedule, so it only stores the hint for the
+    /// executor. The [`ThreadPoolExecutor`](crate::ThreadPoolExecutor) runs
+    /// the thread on the worker of that number only.
+    pub fn with_affinity(mut self, core: usize) -> Self {
+        self.set_affinity(core);
+        self
+    }
+
+    /// Pin the thread to the core numbered `core`. See [`with_affinity`](Self::with_affinity).
+    pub fn set_affinity(&mut self, core: usize) {
+        unsafe {
+            self.tcb.hints.set_affinity(core);
+        }
+    }
+
+    /// Get the core the thread is pinned to, if any.
+    pub fn affinity(&self) -> Option<usize> {
+        unsafe { self.tcb.hints.affinity() }
+    }
+
+    /// Get the program counter where the parked thread will resume.
+    ///
+    /// # Safety
+    ///
+    /// The thread must have been polled and is not running now.
+    pub unsafe fn saved_pc(&self) -> usize {
+        debug_assert!(!self.tcb.context_ptr.is_null(), "thread not started");
+        (*self.tcb.context_ptr).pc()
+    }
+
+    /// Get the raw bytes of the saved context of the parked thread.
+    ///
+    /// Registers can be read from them by the offsets in [`layout`].
+    ///
+    /// # Safety
+    ///
+    /// The thread must have been polled and is not running now.
+    pub unsafe fn saved_context_bytes(&self) -> &[u8] {
+        debug_assert!(!self.tcb.context_ptr.is_null(), "thread not started");
+        ThreadContext::from_ptr(self.tcb.context_ptr as usize).as_bytes()
+    }
+
+    /// Get the stack pointer of the parked thread when it switched out.
+    ///
+    /// # Safety
+    ///
+    /// The thread must have been polled and is not running now.
+    pub unsafe fn saved_sp(&self) -> usize {
+        debug_assert!(!self.tcb.context_ptr.is_null(), "thread not started");
+        self.tcb.context_ptr.add(1) as usize
+    }
+}
+
+impl<F, T> Future for ThreadFuture<F, T>
+where
+    F: 'static + Unpin + FnOnce() -> T,
+    T: 'static,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.poll_detailed(cx) {
+            DetailedPoll::Ready(ret) => Poll::Ready(ret),
+            DetailedPoll::Yielded | DetailedPoll::Parked => Poll::Pending,
+        }
+    }
+}
+
+impl<F, T> ThreadFuture<F, T>
+where
+    F: 'static + Unpin + FnOnce() -> T,
+    T: 'static,
+{
+    /// Poll the thread like [`Future::poll`], telling how it switched out if pending.
+    ///
+    /// An executor may poll a [`Yielded`](DetailedPoll::Yielded) thread again
+    /// soon, and should not poll a [`Parked`](DetailedPoll::Parked) one until
+    /// it is woken. Why it switched out is [`yield_reason`](Self::yield_reason).
+    pub fn poll_detailed(self: Pin<&mut Self>, cx: &mut Context<'_>) -> DetailedPoll<T> {
+        // allocate executor context at stack
+        // the return value is moved out by value and never pinned, so `T` needs not be `Unpin`
+        let raw = unsafe { self.get_unchecked_mut() };
+        // switching to a running thread would corrupt its stack
+        assert!(
+            unsafe { !raw.tcb.on_cpu },
+            "re-entrant poll: the thread is already running"
+        );
+        let state = unsafe {
+            // fill SP and PC at first run
             if let State::Ready(_) = &raw.tcb.state {
-                let context = ((raw as *mut Self).add(1) as *mut ThreadContext).sub(1);
-                (*context).set_pc(entry::<F, T> as usize);
-                raw.tcb.context_ptr = context;
+                let configure = mpu_hook(&MPU_CONFIGURATOR);
+                #[cfg(feature = "overflow-mpu")]
+                assert!(
+                    configure.is_some(),
+                    "no stack overflow protection: set an MPU configurator"
+                );
+                if let Some(configure) = configure {
+                    let (base, size) = raw.tcb.stack_region();
+                    configure(base, size);
+                }
+                if raw.tcb.poison {
+                    let (base, size) = raw.tcb.stack_region();
+                    core::ptr::write_bytes(base as *mut u8, STACK_POISON, size);
+                }
+                let color = raw.tcb.color.unwrap_or(0) as usize;
+                let top = (raw as *mut Self).add(1) as usize - color;
+                raw.tcb.context_ptr = ThreadContext::init(
+                    top,
+                    entry::<F, T, YieldOnExit> as usize,
+                    &mut *raw.tcb as *mut TCB<F, T> as usize,
+                );
+                // the thread starts with the optional registers of the executor
+                let regs = raw.tcb.regs;
+                raw.tcb.ext.save(regs);
+                #[cfg(feature = "std")]
+                if let Some(abort) = &raw.tcb.abort {
+                    abort.set_waker(cx.waker().clone());
+                }
+            }
+            // set at the first run, or again after `take_current_waker`
+            if raw.tcb.waker.is_none() {
                 raw.tcb.waker = Some(cx.waker().clone());
             }
+            // a safe caller can reach these by moving or re-polling an `Unpin` future,
+            // so they must be checked in release builds too. A moved thread is only
+            // caught here if it is polled again, see the docs of `ThreadFuture`.
+            if raw.tcb.state.is_finished() {
+                panic!("polled after completion");
+            }
+            let start = raw as *mut Self as usize;
+            assert!(
+                (start..start + RAW_SIZE).contains(&(raw.tcb.context_ptr as usize)),
+                "the thread is moved after being polled"
+            );
             // switch to the thread
-            ThreadContext::switch(&mut raw.tcb.context_ptr);
+            raw.tcb.budget_left = raw.tcb.yield_budget;
+            raw.tcb.on_cpu = true;
+            let regs = raw.tcb.regs;
+            if regs == RegSet::INT {
+                ThreadContext::switch_counted(&mut raw.tcb.context_ptr);
+            } else {
+                // the swap is done in asm around the switch, since compiled code
+                // in between may keep its own values in those registers
+                ThreadContext::switch_ext_counted(&mut raw.tcb.context_ptr, &mut raw.tcb.ext, regs);
+            }
+            raw.tcb.on_cpu = false;
+            let kind = || match &raw.tcb.state {
+                State::Running if raw.tcb.hints.yielded => YieldKind::Yield,
+                State::Running => YieldKind::Park,
+                _ => YieldKind::Exit,
+            };
+            #[cfg(feature = "defmt-trace")]
+            defmt::trace!(
+                "thread {} switched back: {} ({})",
+                raw.id(),
+                kind(),
+                raw.tcb.hints.reason()
+            );
+            if let Some(hook) = post_yield_hook() {
+                hook(raw.id(), kind(), raw.tcb.hints.reason());
+            }
             &mut raw.tcb.state
         };
         // check the thread state
+        #[cfg(feature = "std")]
+        {
+            if let State::Panicked(_) = state {
+                if let State::Panicked(payload) = core::mem::replace(state, State::Unwound) {
+                    std::panic::resume_unwind(payload);
+                }
+            }
+        }
         if let Some(ret) = state.take_ret() {
             // exited
-            Poll::Ready(ret)
+            #[cfg(all(debug_assertions, feature = "dangling-check"))]
+            raw.check_dangling(&ret);
+            DetailedPoll::Ready(ret)
+        } else if unsafe { raw.tcb.hints.yielded } {
+            DetailedPoll::Yielded
         } else {
-            // yield_now or park
-            Poll::Pending
+            DetailedPoll::Parked
         }
     }
 }
 
-/// A static function as the entry of new thread
-unsafe extern "C" fn entry<F, T>()
+#[cfg(all(debug_assertions, feature = "dangling-check"))]
+impl<F, T> ThreadFuture<F, T> {
+    /// Warn if a pointer-sized return value points into this thread.
+    ///
+    /// It is a heuristic: an integer which happens to be in range is reported too.
+    fn check_dangling(&self, ret: &T) {
+        if core::mem::size_of::<T>() != core::mem::size_of::<usize>() {
+            return;
+        }
+        let value = unsafe { core::mem::transmute_copy::<T, usize>(ret) };
+        let start = self as *const Self as usize;
+        if !(start..start + RAW_SIZE).contains(&value) {
+            return;
+        }
+        let info = DanglingReturnInfo {
+            name: unsafe { self.tcb.name.get() },
+            start,
+            value,
+        };
+        #[cfg(feature = "defmt")]
+        defmt::warn!("thread may return a dangling pointer: {}", info);
+        let logger = *OVERFLOW_LOGGER.lock();
+        if let Some(logger) = logger {
+            logger.report_dangling(&info);
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<F, T> ThreadFuture<F, T> {
+    /// Warn that the thread is dropped before it exited.
+    fn warn_dropped(&self) {
+        let info = DroppedThreadInfo {
+            name: unsafe { self.tcb.name.get() },
+            start: self as *const Self as usize,
+        };
+        #[cfg(feature = "defmt")]
+        defmt::warn!("thread dropped before it exited: {}", info);
+        let logger = *OVERFLOW_LOGGER.lock();
+        if let Some(logger) = logger {
+            logger.report_dropped(&info);
+        }
+    }
+}
+
+impl<F, T> Drop for ThreadFuture<F, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            if let State::Running = unsafe { &self.tcb.state } {
+                self.warn_dropped();
+            }
+        }
+        unsafe {
+            // the node lives on the stack which is about to be freed
+            if !self.tcb.waiting.is_null() {
+                sync::WaitLink::unlink(self.tcb.waiting);
+            }
+            // the context is filled at the first poll
+            if !self.tcb.context_ptr.is_null() {
+                if let Some(teardown) = mpu_hook(&MPU_TEARDOWN) {
+                    let (base, size) = self.tcb.stack_region();
+                    teardown(base, size);
+                }
+            }
+            ManuallyDrop::drop(&mut self.tcb);
+        }
+        STACK_BYTES.fetch_sub(RAW_SIZE, Ordering::Relaxed);
+    }
+}
+
+impl<F, T> ThreadFuture<F, T>
 where
-    F: Send + 'static + FnOnce() -> T,
-    T: Send + 'static,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
 {
-    let tcb = TCB::<F, T>::current();
-    if let State::Ready(f) = core::mem::replace(&mut tcb.state, State::Running) {
-        let ret = f();
-        tcb.state = State::Exited(ret);
-    } else {
-        unreachable!()
+    /// Create a thread in memory provided by the caller, without moving it.
+    ///
+    /// Only the TCB at the start of the region is initialized.
+    ///
+    /// # Safety
+    ///
+    /// - `ptr` must be aligned to 0x2000, and valid for reads and writes of 0x2000 bytes.
+    /// - The region must not be accessed except through the returned reference,
+    ///   and must outlive the lifetime `'a` chosen by the caller.
+    /// - The caller owns the thread. It should be dropped by `ptr::drop_in_place`
+    ///   before the region is freed or reused, otherwise the TCB is leaked.
+    pub unsafe fn from_raw<'a>(ptr: NonNull<u8>, f: F) -> &'a mut Self {
+        assert_eq!(core::mem::size_of::<Self>(), RAW_SIZE, "TCB size exceed");
+        assert_eq!(ptr.as_ptr() as usize % RAW_SIZE, 0, "misaligned region");
+        (ptr.as_ptr() as *mut TCB<F, T>).write(TCB::new(f));
+        &mut *(ptr.as_ptr() as *mut Self)
     }
-    yield_now();
-    unreachable!();
-}
 
-/// Cooperatively gives up the CPU to the executor.
-///
-/// # Example
-/// TODO
-pub fn yield_now() {
-    unsafe {
-        // type `F` and `T` do not matter
-        let tcb = TCB::<fn(), ()>::current();
-        // wake up myself, otherwise the executor won't poll me again
-        tcb.waker.as_ref().unwrap().wake_by_ref();
-        // switch back to the executor thread
-        ThreadContext::switch(&mut tcb.context_ptr);
+    /// Convert a closure of blocking thread to future, within the stack budget.
+    ///
+    /// Returns the closure back if the thread would take the total memory
+    /// of all threads over the budget set by [`set_stack_budget`].
+    /// `From` ignores the budget, but its threads are still accounted.
+    pub fn try_new(f: F) -> Result<Self, F> {
+        assert_eq!(core::mem::size_of::<Self>(), RAW_SIZE, "TCB size exceed");
+        let reserved = STACK_BYTES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+            let total = bytes.checked_add(RAW_SIZE)?;
+            if total > STACK_BUDGET.load(Ordering::Relaxed) {
+                return None;
+            }
+            Some(total)
+        });
+        if reserved.is_err() {
+            return Err(f);
+        }
+        // the reservation is released when the thread is dropped
+        Ok(ThreadFuture {
+            tcb: ManuallyDrop::new(TCB::unaccounted(f)),
+        })
+    }
+
+    /// Clone a thread which has not been polled yet.
+    ///
+    /// The copy gets a fresh TCB with a clone of the closure, keeping only
+    /// the name. Returns `None` if the thread has started.
+    pub fn try_clone(&self) -> Option<Self>
+    where
+        F: Clone,
+    {
+        match unsafe { &self.tcb.state } {
+            State::Ready(f) => {
+                let mut thread = Self::from(f.clone());
+                unsafe {
+                    (*thread.tcb).name = self.tcb.name;
+                }
+                Some(thread)
+            }
+            _ => None,
+        }
+    }
+
+    /// Reuse the memory of a finished thread to run `f`.
+    ///
+    /// The thread must have exited or panicked, and its result must have been
+    /// taken by a poll. Then nothing lives on the stack, and the TCB is rebuilt
+    /// in place as if the thread were new, keeping only the name.
+    ///
+    /// Panics otherwise, telling a thread which is parked or running, whose
+    /// frames would be lost, from one not started or with its result untaken.
+    pub fn reset(&mut self, f: F) {
+        unsafe {
+            match &self.tcb.state {
+                State::Running => panic!("reset a thread which is parked or running"),
+                State::Ready(_) => panic!("reset a thread which has not started"),
+                state if !state.is_finished() => {
+                    panic!("reset a thread whose result has not been taken")
+                }
+                _ => {}
+            }
+            if let Some(teardown) = mpu_hook(&MPU_TEARDOWN) {
+                let (base, size) = self.tcb.stack_region();
+                teardown(base, size);
+            }
+            let name = self.tcb.name;
+            ManuallyDrop::drop(&mut self.tcb);
+            // the thread is still accounted once
+            self.tcb = ManuallyDrop::new(TCB::unaccounted(f));
+            (*self.tcb).name = name;
+        }
+    }
+
+    /// Poll the thread without pinning it first, like `FutureExt::poll_unpin`.
+    pub fn poll_unpin(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+        Pin::new(self).poll(cx)
+    }
+
+    /// Poll the thread, re-entering it up to `n` times while it keeps yielding.
+    ///
+    /// Returns `Pending` after the thread parks, or after `n` switches.
+    /// A large `n` saves scheduler overhead for threads which yield often but
+    /// make quick progress, at the cost of delaying other tasks of the executor.
+    /// The wake-ups of yields are still sent, so the task may be polled spuriously.
+    pub fn poll_n(&mut self, cx: &mut Context<'_>, n: usize) -> Poll<T> {
+        for _ in 0..n {
+            if let Poll::Ready(ret) = self.poll_unpin(cx) {
+                return Poll::Ready(ret);
+            }
+            if !self.is_yielded() {
+                break;
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Run another blocking thread with the return value of this one.
+    ///
+    /// `g` is called on the executor after this thread exited.
+    /// The new thread runs on a fresh stack built by `g`,
+    /// which takes the place of this one only after it has been torn down.
+    pub fn and_then<G, F2, U>(self, g: G) -> impl Future<Output = U>
+    where
+        G: FnOnce(T) -> ThreadFuture<F2, U> + Unpin,
+        F2: Send + 'static + Unpin + FnOnce() -> U,
+        U: Send + 'static + Unpin,
+    {
+        AndThen::First(self, Some(g))
+    }
+
+    /// Call `f` on the return value, then output it unchanged.
+    ///
+    /// `f` is called exactly once on the executor after this thread exited.
+    pub fn inspect<G>(self, f: G) -> impl Future<Output = T>
+    where
+        G: FnOnce(&T) + Unpin,
+    {
+        Inspect {
+            thread: self,
+            f: Some(f),
+        }
+    }
+
+    /// Make the thread safe to poll after completion.
+    ///
+    /// The thread is dropped once it exits, then any later poll
+    /// returns `Poll::Pending` without switching to the dead context.
+    pub fn fuse(self) -> FusedThreadFuture<F, T> {
+        FusedThreadFuture { thread: Some(self) }
     }
 }
 
-/// Blocks unless or until the current thread's token is made available.
-pub fn park() {
-    unsafe {
-        // type `F` and `T` do not matter
-        let tcb = TCB::<fn(), ()>::current();
-        // switch back to the executor thread
-        ThreadContext::switch(&mut tcb.context_ptr);
+/// Future for [`ThreadFuture::inspect`].
+struct Inspect<F, T, G> {
+    thread: ThreadFuture<F, T>,
+    f: Option<G>,
+}
+
+impl<F, T, G> Future for Inspect<F, T, G>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+    G: FnOnce(&T) + Unpin,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let ret = match this.thread.poll_unpin(cx) {
+            Poll::Ready(ret) => ret,
+            Poll::Pending => return Poll::Pending,
+        };
+        let f = this.f.take().expect("inspect polled after completion");
+        f(&ret);
+        Poll::Ready(ret)
     }
 }
 
-/// Get waker of the current thread.
-pub fn current_waker() -> Waker {
-    unsafe {
-        // type `F` and `T` do not matter
-        let tcb = TCB::<fn(), ()>::current();
-        tcb.waker.as_ref().unwrap().clone()
+/// Future for [`ThreadFuture::fuse`].
+pub struct FusedThreadFuture<F, T> {
+    thread: Option<ThreadFuture<F, T>>,
+}
+
+impl<F, T> FusedThreadFuture<F, T> {
+    /// Whether the thread has exited and the return value has been taken.
+    pub fn is_terminated(&self) -> bool {
+        self.thread.is_none()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Duration;
+impl<F, T> Future for FusedThreadFuture<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    type Output = T;
 
-    #[tokio::test]
-    async fn test() {
-        let h1 = tokio::spawn(ThreadFuture::from(|| {
-            println!("1.1");
-            yield_now();
-            println!("1.2");
-            1u32
-        }));
-        let h2 = tokio::spawn(ThreadFuture::from(|| {
-            println!("2.1");
-            yield_now();
-            println!("2.2");
-            2u32
-        }));
-        assert_eq!(h1.await.unwrap(), 1);
-        assert_eq!(h2.await.unwrap(), 2);
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let thread = match this.thread.as_mut() {
+            Some(thread) => thread,
+            None => return Poll::Pending,
+        };
+        let ret = match thread.poll_unpin(cx) {
+            Poll::Ready(ret) => ret,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.thread = None;
+        Poll::Ready(ret)
     }
+}
 
-    #[tokio::test]
-    async fn sleep_and_wake() {
-        let h1 = tokio::spawn(ThreadFuture::from(|| {
-            let waker = current_waker();
-            tokio::spawn(async move {
-                tokio::time::delay_for(Duration::from_millis(10)).await;
+/// Future for [`ThreadFuture::and_then`].
+enum AndThen<F, T, G, F2, U> {
+    First(ThreadFuture<F, T>, Option<G>),
+    Second(ThreadFuture<F2, U>),
+}
+
+impl<F, T, G, F2, U> Future for AndThen<F, T, G, F2, U>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+    G: FnOnce(T) -> ThreadFuture<F2, U> + Unpin,
+    F2: Send + 'static + Unpin + FnOnce() -> U,
+    U: Send + 'static + Unpin,
+{
+    type Output = U;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            match this {
+                AndThen::First(first, g) => match Pin::new(first).poll(cx) {
+                    Poll::Ready(ret) => {
+                        let g = g.take().expect("and_then polled after completion");
+                        *this = AndThen::Second(g(ret));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                AndThen::Second(second) => return Pin::new(second).poll(cx),
+            }
+        }
+    }
+}
+
+/// The result of [`select2`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    /// The first thread exited first.
+    Left(A),
+    /// The second thread exited first.
+    Right(B),
+}
+
+/// Wait for the first of two threads to exit, then drop the other one.
+///
+/// The threads are polled in order, so `a` wins if both can exit in the same poll.
+///
+/// The loser is cancelled by dropping it at any yield point. Its stack is discarded
+/// without unwinding, so the destructors of values living on it will not run.
+pub fn select2<FA, A, FB, B>(
+    a: ThreadFuture<FA, A>,
+    b: ThreadFuture<FB, B>,
+) -> impl Future<Output = Either<A, B>>
+where
+    FA: Send + 'static + Unpin + FnOnce() -> A,
+    A: Send + 'static + Unpin,
+    FB: Send + 'static + Unpin + FnOnce() -> B,
+    B: Send + 'static + Unpin,
+{
+    Select2 {
+        a: Some(a),
+        b: Some(b),
+    }
+}
+
+/// Future for [`select2`].
+struct Select2<FA, A, FB, B> {
+    a: Option<ThreadFuture<FA, A>>,
+    b: Option<ThreadFuture<FB, B>>,
+}
+
+impl<FA, A, FB, B> Future for Select2<FA, A, FB, B>
+where
+    FA: Send + 'static + Unpin + FnOnce() -> A,
+    A: Send + 'static + Unpin,
+    FB: Send + 'static + Unpin + FnOnce() -> B,
+    B: Send + 'static + Unpin,
+{
+    type Output = Either<A, B>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let a = this.a.as_mut().expect("select2 polled after completion");
+        let ret = if let Poll::Ready(ret) = Pin::new(a).poll(cx) {
+            Either::Left(ret)
+        } else if let Poll::Ready(ret) = Pin::new(this.b.as_mut().unwrap()).poll(cx) {
+            Either::Right(ret)
+        } else {
+            return Poll::Pending;
+        };
+        // cancel the loser
+        this.a = None;
+        this.b = None;
+        Poll::Ready(ret)
+    }
+}
+
+/// A static function as the entry of new thread
+///
+/// The TCB is passed in the first argument register, which saves looking it up from SP.
+/// After the closure returns, the thread exits by `E`.
+unsafe extern "C" fn entry<F, T, E>(tcb: *mut TCB<F, T>)
+where
+    F: 'static + FnOnce() -> T,
+    T: 'static,
+    E: OnExit,
+{
+    debug_assert_eq!(tcb, TCB::<F, T>::current() as *mut _);
+    let tcb = &mut *tcb;
+    if let Some(prologue) = tcb.prologue {
+        prologue();
+    }
+    if let State::Ready(f) = core::mem::replace(&mut tcb.state, State::Running) {
+        // unwinding out of `entry` is undefined, so catch it and resume on the executor
+        #[cfg(feature = "std")]
+        {
+            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+                Ok(ret) => tcb.state = State::Exited(ret),
+                Err(payload) => match payload.downcast::<exit::ExitValue<T>>() {
+                    Ok(exit) => tcb.state = State::Exited(exit.0),
+                    Err(payload) => tcb.state = State::Panicked(payload),
+                },
+            }
+            // `E` may yield, which must not unwind again
+            tcb.abort = None;
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            let ret = f();
+            tcb.state = State::Exited(ret);
+        }
+    } else {
+        unreachable!()
+    }
+    // the yield on exit must switch out, whatever budget is left
+    tcb.budget_left = 1;
+    E::on_exit()
+}
+
+/// What a thread does after its closure returns and the return value is
+/// stored in the TCB.
+///
+/// It must never return, since there is nothing to return to.
+pub(crate) trait OnExit {
+    fn on_exit() -> !;
+}
+
+/// Wake the executor to collect the return value.
+pub(crate) struct YieldOnExit;
+
+impl OnExit for YieldOnExit {
+    fn on_exit() -> ! {
+        yield_now();
+        unreachable!();
+    }
+}
+
+/// Cooperatively gives up the CPU to the executor.
+///
+/// # Example
+/// TODO
+pub fn yield_now() {
+    yield_with_priority(Priority::Normal);
+}
+
+/// Cooperatively gives up the CPU to the executor, with a priority hint.
+///
+/// The hint can be read by the executor from [`ThreadFuture::priority`].
+pub fn yield_with_priority(priority: Priority) {
+    yield_with(priority, Enqueue::Back, YieldReason::Voluntary);
+}
+
+/// Cooperatively gives up the CPU to the executor, telling why.
+///
+/// The reason is seen by the post-yield hook set by [`set_post_yield_hook`],
+/// and can be read by the executor from [`ThreadFuture::yield_reason`].
+pub fn yield_with_reason(reason: YieldReason) {
+    yield_with(Priority::Normal, Enqueue::Back, reason);
+}
+
+/// Cooperatively gives up the CPU, asking to be put at the front of the ready queue.
+///
+/// The hint can be read by the executor from [`ThreadFuture::enqueue_hint`].
+pub fn yield_now_front() {
+    yield_with(Priority::Normal, Enqueue::Front, YieldReason::Voluntary);
+}
+
+/// Cooperatively gives up the CPU, asking to be put at the back of the ready queue.
+///
+/// This is what [`yield_now`] does.
+pub fn yield_now_back() {
+    yield_with(Priority::Normal, Enqueue::Back, YieldReason::Voluntary);
+}
+
+fn yield_with(priority: Priority, enqueue: Enqueue, reason: YieldReason) {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current_hot();
+        // before the budget, which is never set on the main thread
+        assert!(!tcb.context_ptr.is_null(), "the main thread cannot switch out");
+        tcb.hints.record_yield(priority, enqueue, reason);
+        #[cfg(feature = "std")]
+        tcb.check_abort();
+        // keep running while the budget of this poll lasts
+        tcb.budget_left -= 1;
+        if tcb.budget_left > 0 {
+            return;
+        }
+        // wake up myself, otherwise the executor won't poll me again
+        // if the waker is taken, whoever took it must wake me
+        if let Some(waker) = &tcb.waker {
+            waker.wake_by_ref();
+        }
+        // switch back to the executor thread
+        ThreadContext::switch_counted(&mut tcb.context_ptr);
+        #[cfg(feature = "std")]
+        tcb.check_abort();
+    }
+}
+
+/// Run `f` with the canary check of the current thread disabled.
+///
+/// Yields and other calls in `f` skip the check of the overflow canary, which
+/// saves its cost in an audited hot loop without disabling it for the whole
+/// crate. The canaries are checked once on entry and once on exit, so an
+/// overflow in `f` is still caught, only late: whatever it clobbered in the
+/// TCB meanwhile, such as the context or the waker, is used unchecked.
+/// Only use it if the stack depth of `f` is known to be well within bounds.
+///
+/// Calls may nest. Panics if the current code is not in a green thread.
+pub fn without_canary_check<R>(f: impl FnOnce() -> R) -> R {
+    /// Restore the check on exit, even by unwinding.
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            unsafe {
+                let tcb = TCB::<fn(), ()>::current_unchecked();
+                tcb.skip_canary = self.0;
+                if !self.0 {
+                    tcb.check_canary();
+                }
+            }
+        }
+    }
+
+    assert!(in_green_thread(), "not in a green thread");
+    let _restore = unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current();
+        let restore = Restore(tcb.skip_canary);
+        tcb.skip_canary = true;
+        restore
+    };
+    f()
+}
+
+/// Tell the executor how long the current thread expects to run between yields.
+///
+/// The hint stays until it is changed, and can be read by the executor
+/// from [`ThreadFuture::run_class`]. Threads start as [`RunClass::Medium`].
+pub fn hint_duration(class: RunClass) {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current();
+        tcb.hints.set_run_class(class);
+    }
+}
+
+/// Set the deadline of the current thread, in ticks of a clock known to the executor.
+///
+/// `None` clears it. It can be read by the executor from [`ThreadFuture::deadline`].
+pub fn set_deadline(deadline: Option<u64>) {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current();
+        tcb.hints.set_deadline(deadline);
+    }
+}
+
+/// Get the deadline of the current thread.
+///
+/// Yield points of the thread may consult it to decide how urgent it is.
+pub fn current_deadline() -> Option<u64> {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current();
+        tcb.hints.deadline()
+    }
+}
+
+/// Blocks unless or until the current thread's token is made available.
+pub fn park() {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current_hot();
+        tcb.hints.record_park();
+        #[cfg(feature = "std")]
+        tcb.check_abort();
+        // switch back to the executor thread
+        assert!(!tcb.context_ptr.is_null(), "the main thread cannot switch out");
+        ThreadContext::switch_counted(&mut tcb.context_ptr);
+        #[cfg(feature = "std")]
+        tcb.check_abort();
+    }
+}
+
+/// Get waker of the current thread.
+///
+/// Panics if it has been taken by [`take_current_waker`] in this poll.
+pub fn current_waker() -> Waker {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current_hot();
+        tcb.waker.as_ref().expect("the waker is taken").clone()
+    }
+}
+
+/// Move the waker of the current thread out, instead of cloning it.
+///
+/// It saves a clone for reactors which keep the waker in their own wait list.
+/// Until the next poll, nothing else can wake the thread: a [`park`] or even a
+/// [`yield_now`] hangs unless the taken waker is woken, so register it before
+/// switching out. The next poll stores the waker of the executor again.
+///
+/// Returns `None` if it has been taken in this poll.
+pub fn take_current_waker() -> Option<Waker> {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current_hot();
+        tcb.waker.take()
+    }
+}
+
+/// Hook to configure MPU for a thread stack.
+static MPU_CONFIGURATOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Hook to release MPU configuration of a thread stack.
+static MPU_TEARDOWN: AtomicUsize = AtomicUsize::new(0);
+
+fn mpu_hook(hook: &AtomicUsize) -> Option<fn(usize, usize)> {
+    match hook.load(Ordering::Acquire) {
+        0 => None,
+        f => Some(unsafe { core::mem::transmute::<usize, fn(usize, usize)>(f) }),
+    }
+}
+
+/// Set a function to configure MPU for the stack of a thread.
+///
+/// It is called with `(base, size)` of the usable stack when a thread is
+/// polled for the first time. The stack grows downwards from `base + size`,
+/// so a no-access region at `base` catches stack overflow in hardware.
+/// With the `overflow-mpu` feature, it must be set before the first poll.
+pub fn set_mpu_configurator(configure: fn(base: usize, size: usize)) {
+    MPU_CONFIGURATOR.store(configure as usize, Ordering::Release);
+}
+
+/// Set a function to release the MPU configuration of a thread.
+///
+/// It is called with the same `(base, size)` as the configurator
+/// when a thread which has been polled is dropped.
+pub fn set_mpu_teardown(teardown: fn(base: usize, size: usize)) {
+    MPU_TEARDOWN.store(teardown as usize, Ordering::Release);
+}
+
+/// Hook called on the executor stack after a thread switches back.
+static POST_YIELD_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+fn post_yield_hook() -> Option<fn(ThreadId, YieldKind, YieldReason)> {
+    match POST_YIELD_HOOK.load(Ordering::Acquire) {
+        0 => None,
+        f => Some(unsafe {
+            core::mem::transmute::<usize, fn(ThreadId, YieldKind, YieldReason)>(f)
+        }),
+    }
+}
+
+/// Set a function called on the executor stack each time a thread switches back.
+///
+/// It runs inside `poll` right after the switch, before `poll` returns,
+/// so it is a place to record timing or update scheduler structures.
+/// A panic of the thread is resumed after the hook sees `YieldKind::Exit`.
+/// The [`YieldReason`] is the one given at the last switch out.
+pub fn set_post_yield_hook(hook: fn(ThreadId, YieldKind, YieldReason)) {
+    POST_YIELD_HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Details of a detected stack overflow.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackOverflowInfo {
+    /// Name of the overflowed thread.
+    pub name: Option<&'static str>,
+    /// Lowest address of the usable stack.
+    pub stack_base: usize,
+    /// Size of the usable stack.
+    pub stack_size: usize,
+    /// Address of the changed canary.
+    pub canary_addr: usize,
+    /// The value the canary should hold.
+    pub expected: usize,
+    /// The value found in the canary.
+    pub found: usize,
+}
+
+/// A sink for stack overflow reports.
+///
+/// Kernels can route reports to their UART or semihosting,
+/// where the panic message may have nowhere to go.
+pub trait OverflowLogger: Sync {
+    /// Report an overflow. It is called right before the canary panic.
+    fn report(&self, info: &StackOverflowInfo);
+
+    /// Warn that a thread returned a pointer into its own stack.
+    ///
+    /// It is only called with the `dangling-check` feature in debug builds.
+    fn report_dangling(&self, _info: &DanglingReturnInfo) {}
+
+    /// Warn that a thread was dropped before it exited.
+    ///
+    /// The locals on its stack are leaked, which is often an accidental
+    /// cancellation. It is only called in debug builds.
+    fn report_dropped(&self, _info: &DroppedThreadInfo) {}
+}
+
+/// Details of a return value which may point into the freed stack.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DanglingReturnInfo {
+    /// Name of the thread.
+    pub name: Option<&'static str>,
+    /// Lowest address of the memory of the thread, including the TCB.
+    pub start: usize,
+    /// The suspicious return value.
+    pub value: usize,
+}
+
+/// Details of a thread dropped while parked in the middle of its closure.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedThreadInfo {
+    /// Name of the thread.
+    pub name: Option<&'static str>,
+    /// Lowest address of the memory of the thread, including the TCB.
+    pub start: usize,
+}
+
+/// Hook to handle a stack overflow instead of panicking.
+static STACK_FAULT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Set a function to handle stack overflows instead of panicking.
+///
+/// It is called with the details when a changed canary is found,
+/// after the overflow logger. It decides the policy, e.g. to kill the task,
+/// reset the system, or log and halt. Without it, the check panics.
+pub fn set_stack_fault_handler(handler: fn(StackOverflowInfo) -> !) {
+    STACK_FAULT_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+/// The overflow logger set by [`set_overflow_logger`].
+static OVERFLOW_LOGGER: SpinLock<Option<&'static dyn OverflowLogger>> = SpinLock::new(None);
+
+/// Set a logger to report stack overflows before panicking.
+pub fn set_overflow_logger(logger: &'static dyn OverflowLogger) {
+    *OVERFLOW_LOGGER.lock() = Some(logger);
+}
+
+/// Number of switches into and out of all threads.
+#[cfg(feature = "metrics")]
+static TOTAL_SWITCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the number of context switches into and out of all threads so far.
+///
+/// A poll which runs a thread until it yields, parks or exits counts two.
+/// Yields within the budget of [`with_yield_budget`](ThreadFuture::with_yield_budget)
+/// do not switch, so they are not counted.
+#[cfg(feature = "metrics")]
+pub fn total_context_switches() -> usize {
+    TOTAL_SWITCHES.load(Ordering::Relaxed)
+}
+
+/// Bytes of memory taken by all live threads, including their TCBs.
+static STACK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The cap of `STACK_BYTES` checked by [`ThreadFuture::try_new`].
+static STACK_BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Get the bytes of memory taken by all live threads.
+///
+/// Each thread takes 0x2000 bytes for its TCB and stack
+/// from its construction until it is dropped.
+pub fn total_stack_bytes() -> usize {
+    STACK_BYTES.load(Ordering::Relaxed)
+}
+
+/// Cap the total memory of all threads created by [`ThreadFuture::try_new`].
+///
+/// It gives back-pressure against runaway spawning, e.g. by
+/// [`ThreadPoolExecutor::try_spawn`](crate::ThreadPoolExecutor::try_spawn).
+/// Threads created before are not affected, even if they are over it.
+pub fn set_stack_budget(max: usize) {
+    STACK_BUDGET.store(max, Ordering::Relaxed);
+}
+
+/// Number of thread local slots of each thread.
+pub const GREEN_TLS_SLOTS: usize = 4;
+
+/// Get the value of a thread local slot of the current thread.
+///
+/// Slots start as 0. What each index means is a convention of the user.
+/// Panics if `index` is not less than [`GREEN_TLS_SLOTS`].
+pub fn green_tls_get(index: usize) -> usize {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current();
+        tcb.tls[index]
+    }
+}
+
+/// Set the value of a thread local slot of the current thread.
+///
+/// Panics if `index` is not less than [`GREEN_TLS_SLOTS`].
+pub fn green_tls_set(index: usize, value: usize) {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current();
+        tcb.tls[index] = value;
+    }
+}
+
+/// Whether the current code is running inside a green thread.
+///
+/// Unlike other functions here, it never panics outside a green thread.
+/// It reads the memory at the 0x2000-aligned base of the current stack,
+/// which must be readable. Without the `overflow-canary` feature there is no
+/// canary to recognize a TCB by, so it only checks that the fields are plausible.
+pub fn in_green_thread() -> bool {
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = &*((stack_pointer() & !(RAW_SIZE - 1)) as *const TCB<fn(), ()>);
+        #[cfg(feature = "overflow-canary")]
+        let valid = tcb.canary_offset < RAW_SIZE
+            && tcb.canary_offset & (core::mem::align_of::<usize>() - 1) == 0
+            && *tcb.canary() == CANARY;
+        #[cfg(not(feature = "overflow-canary"))]
+        let valid = tcb.yield_budget != 0 && tcb.budget_left <= tcb.yield_budget;
+        // the flag may be garbage, which is not a valid `bool`
+        valid && *(&tcb.on_cpu as *const bool as *const u8) == 1
+    }
+}
+
+/// Get name of the current thread.
+///
+/// Returns `None` if the thread is unnamed, or if the current code is not in
+/// a green thread, as told by [`in_green_thread`].
+///
+/// It never panics, so it may be called from a `#[panic_handler]` to tell
+/// which thread panicked. A thread whose top canary is clobbered is not
+/// recognized, but the canary panic and the overflow report name it.
+pub fn current_thread_name() -> Option<&'static str> {
+    if !in_green_thread() {
+        return None;
+    }
+    unsafe {
+        // type `F` and `T` do not matter
+        let tcb = TCB::<fn(), ()>::current_unchecked();
+        tcb.name.get()
+    }
+}
+
+/// Byte layout of threads, for debugger scripts reading parked threads.
+///
+/// The memory of a thread is [`THREAD_SIZE`] bytes aligned to its size,
+/// with the TCB at the base. The TCB holds [`TCB_CONTEXT_PTR`], which points
+/// to the saved context of a parked thread. Its stack pointer is right above
+/// the context, at `context_ptr + CONTEXT_SIZE`.
+///
+/// The TCB is `#[repr(C)]`, and the fields here lead it and are made of
+/// pointers and `usize` only, so their offsets only change with the version
+/// of this crate, not with the compiler. Other fields are not described.
+pub mod layout {
+    use super::*;
+
+    /// Size and alignment of the memory of a thread.
+    pub const THREAD_SIZE: usize = RAW_SIZE;
+
+    /// Offset in the TCB of the pointer to the saved context.
+    pub const TCB_CONTEXT_PTR: usize = 0;
+
+    /// Offset in the TCB of the name.
+    ///
+    /// It is a pointer and a length, and the pointer is null if unnamed.
+    /// The bytes are UTF-8 without a terminating NUL, so in gdb:
+    ///
+    /// ```text
+    /// set $name = *(char **)($base + TCB_NAME)
+    /// set $len = *(unsigned long *)($base + TCB_NAME_LEN)
+    /// if $name
+    ///   printf "%.*s\n", $len, $name
+    /// end
+    /// ```
+    pub const TCB_NAME: usize = core::mem::size_of::<usize>();
+
+    /// Offset in the TCB of the length of the name, valid if it is named.
+    pub const TCB_NAME_LEN: usize = TCB_NAME + core::mem::size_of::<usize>();
+
+    /// Size of the saved context.
+    pub const CONTEXT_SIZE: usize = context_size();
+
+    /// Offsets of the saved registers in the context, by register name.
+    pub const CONTEXT_REGISTERS: &[(&str, usize)] = super::CONTEXT_REGISTERS;
+
+    /// Offset of the program counter in the context.
+    pub const CONTEXT_PC: usize = CONTEXT_PC_OFFSET;
+
+    /// Offset of the frame pointer in the context.
+    pub const CONTEXT_FP: usize = CONTEXT_FP_OFFSET;
+}
+
+/// References every item a backend must provide with its expected signature,
+/// so that a missing or mis-signatured item fails to compile on that arch.
+#[allow(dead_code)]
+mod compile_check {
+    use super::*;
+
+    #[cfg(target_arch = "x86_64")]
+    const SWITCH: unsafe extern "sysv64" fn(*mut *mut ThreadContext) = ThreadContext::switch;
+    #[cfg(not(target_arch = "x86_64"))]
+    const SWITCH: unsafe extern "C" fn(*mut *mut ThreadContext) = ThreadContext::switch;
+    const SET_PC: fn(&mut ThreadContext, usize) = ThreadContext::set_pc;
+    const PC: fn(&ThreadContext) -> usize = ThreadContext::pc;
+    const SET_FP: fn(&mut ThreadContext, usize) = ThreadContext::set_fp;
+    const SET_ENTRY: fn(&mut ThreadContext, usize, usize) = ThreadContext::set_entry;
+    const STACK_POINTER: unsafe fn() -> usize = stack_pointer;
+    const RESERVED: usize = STACK_TOP_RESERVED;
+    const REGISTERS: &[(&str, usize)] = CONTEXT_REGISTERS;
+    const PC_OFFSET: usize = CONTEXT_PC_OFFSET;
+    const FP_OFFSET: usize = CONTEXT_FP_OFFSET;
+    const ENTRY_OFFSET: usize = CONTEXT_ENTRY_OFFSET;
+    const EXT_SAVE: unsafe fn(&mut ExtContext, RegSet) = ExtContext::save;
+    #[cfg(target_arch = "x86_64")]
+    const SWITCH_EXT: unsafe extern "sysv64" fn(
+        *mut *mut ThreadContext,
+        *mut ExtContext,
+        usize,
+        usize,
+    ) = ThreadContext::switch_ext;
+    #[cfg(not(target_arch = "x86_64"))]
+    const SWITCH_EXT: unsafe extern "C" fn(*mut *mut ThreadContext, *mut ExtContext, usize, usize) =
+        ThreadContext::switch_ext;
+    // the saved context must keep the stack 16-byte aligned
+    const CONTEXT_ALIGNED: [(); 0] = [(); core::mem::size_of::<ThreadContext>() % 16];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::{RawWaker, RawWakerVTable};
+    use std::time::Duration;
+
+    pub(crate) fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(core::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(clone(core::ptr::null())) }
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let h1 = tokio::spawn(ThreadFuture::from(|| {
+            println!("1.1");
+            yield_now();
+            println!("1.2");
+            1u32
+        }));
+        let h2 = tokio::spawn(ThreadFuture::from(|| {
+            println!("2.1");
+            yield_now();
+            println!("2.2");
+            2u32
+        }));
+        assert_eq!(h1.await.unwrap(), 1);
+        assert_eq!(h2.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn sleep_and_wake() {
+        let h1 = tokio::spawn(ThreadFuture::from(|| {
+            let waker = current_waker();
+            tokio::spawn(async move {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                waker.wake();
+            });
+            park();
+        }));
+        h1.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn thread_name() {
+        let h1 = tokio::spawn(ThreadFuture::from(current_thread_name).with_name("worker"));
+        let h2 = tokio::spawn(ThreadFuture::from(current_thread_name));
+        assert_eq!(h1.await.unwrap(), Some("worker"));
+        assert_eq!(h2.await.unwrap(), None);
+        // not a green thread, which is no panic
+        assert_eq!(current_thread_name(), None);
+    }
+
+    #[tokio::test]
+    async fn and_then() {
+        let h1 = tokio::spawn(
+            ThreadFuture::from(|| {
+                yield_now();
+                1u32
+            })
+            .and_then(|x| {
+                ThreadFuture::from(move || {
+                    yield_now();
+                    x + 1
+                })
+            }),
+        );
+        assert_eq!(h1.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn mpu_configurator() {
+        static BASE: AtomicUsize = AtomicUsize::new(0);
+        static CONFIGURED: AtomicUsize = AtomicUsize::new(0);
+        static TORN_DOWN: AtomicUsize = AtomicUsize::new(0);
+        set_mpu_configurator(|base, size| {
+            if base == BASE.load(Ordering::SeqCst) {
+                CONFIGURED.store(size, Ordering::SeqCst);
+            }
+        });
+        set_mpu_teardown(|base, size| {
+            if base == BASE.load(Ordering::SeqCst) {
+                TORN_DOWN.store(size, Ordering::SeqCst);
+            }
+        });
+
+        let thread = Box::new(ThreadFuture::from(yield_now));
+        let (base, size) = unsafe { thread.tcb.stack_region() };
+        assert_eq!(base + size, &*thread as *const _ as usize + RAW_SIZE);
+        BASE.store(base, Ordering::SeqCst);
+        tokio::spawn(thread).await.unwrap();
+        assert_eq!(CONFIGURED.load(Ordering::SeqCst), size);
+        assert_eq!(TORN_DOWN.load(Ordering::SeqCst), size);
+    }
+
+    #[tokio::test]
+    async fn green_tls() {
+        let chan = std::sync::Arc::new(Rendezvous::new());
+        let spawn = |value: usize| {
+            let chan = chan.clone();
+            tokio::spawn(ThreadFuture::from(move || {
+                assert_eq!(green_tls_get(0), 0);
+                green_tls_set(0, value);
+                // meet the other thread twice after both set their slots
+                if value == 1 {
+                    chan.send(());
+                    chan.recv();
+                } else {
+                    chan.recv();
+                    chan.send(());
+                }
+                green_tls_get(0)
+            }))
+        };
+        let t1 = spawn(1);
+        let t2 = spawn(2);
+        assert_eq!(t1.await.unwrap(), 1);
+        assert_eq!(t2.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn entry_prologue() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static SP: AtomicUsize = AtomicUsize::new(0);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(
+            ThreadFuture::from(|| {
+                yield_now();
+                CALLS.load(Ordering::SeqCst)
+            })
+            .with_entry_prologue(|| {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+                SP.store(unsafe { stack_pointer() }, Ordering::SeqCst);
+            }),
+        );
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(1));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        let (base, size) = unsafe { thread.tcb.stack_region() };
+        assert!((base..base + size).contains(&SP.load(Ordering::SeqCst)));
+    }
+
+    #[test]
+    fn on_first_poll() {
+        static BUILT: AtomicUsize = AtomicUsize::new(0);
+        fn build() -> impl FnOnce() -> usize {
+            BUILT.fetch_add(1, Ordering::SeqCst);
+            let v = vec![1, 2, 3];
+            move || {
+                yield_now();
+                v.iter().sum()
+            }
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        drop(Box::new(ThreadFuture::with_on_first_poll(build)));
+        let mut thread = Box::new(ThreadFuture::with_on_first_poll(build));
+        assert_eq!(BUILT.load(Ordering::SeqCst), 0);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(BUILT.load(Ordering::SeqCst), 1);
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(6));
+        assert_eq!(BUILT.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn name_at_documented_offset() {
+        let thread = Box::new(ThreadFuture::from(yield_now).with_name("gdb"));
+        let base = &*thread as *const _ as usize;
+        let field = unsafe { &thread.tcb.name as *const _ as usize };
+        assert_eq!(field - base, layout::TCB_NAME);
+        unsafe {
+            let ptr = *((base + layout::TCB_NAME) as *const *const u8);
+            let len = *((base + layout::TCB_NAME_LEN) as *const usize);
+            let bytes = core::slice::from_raw_parts(ptr, len);
+            assert_eq!(bytes, b"gdb");
+        }
+
+        let unnamed = Box::new(ThreadFuture::from(yield_now));
+        let base = &*unnamed as *const _ as usize;
+        assert_eq!(unsafe { *((base + layout::TCB_NAME) as *const usize) }, 0);
+    }
+
+    #[test]
+    fn saved_context_of_parked_thread() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(park));
+        assert!(Pin::new(&mut *thread).poll(&mut cx).is_pending());
+
+        // the thread is blocked inside `park`
+        let pc = unsafe { thread.saved_pc() };
+        assert!(pc > park as usize && pc < park as usize + 0x100);
+        let sp = unsafe { thread.saved_sp() };
+        let (base, size) = unsafe { thread.tcb.stack_region() };
+        assert!(sp > base && sp < base + size);
+    }
+
+    #[cfg(feature = "hints")]
+    #[test]
+    fn priority_hint() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| {
+            yield_with_priority(Priority::High);
+            yield_with_priority(Priority::Low);
+            yield_now();
+        }));
+        assert_eq!(thread.priority(), Priority::Normal);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.priority(), Priority::High);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.priority(), Priority::Low);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.priority(), Priority::Normal);
+        assert!(thread.poll_unpin(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn yielded_or_parked() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| {
+            yield_now();
+            park();
+            yield_now();
+        }));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(thread.is_yielded());
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(!thread.is_yielded());
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(thread.is_yielded());
+    }
+
+    #[test]
+    fn stack_color() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut plain = Box::new(ThreadFuture::from(park));
+        let mut colored = Box::new(ThreadFuture::from(park).with_stack_color(3));
+        assert!(plain.poll_unpin(&mut cx).is_pending());
+        assert!(colored.poll_unpin(&mut cx).is_pending());
+        let plain_offset = unsafe { plain.saved_sp() } - &*plain as *const _ as usize;
+        let colored_offset = unsafe { colored.saved_sp() } - &*colored as *const _ as usize;
+        assert_eq!(plain_offset - colored_offset, 3 * CACHE_LINE);
+        // the thread still finds its TCB
+        colored.check_stack();
+        assert_eq!(colored.poll_unpin(&mut cx), Poll::Ready(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "stack color too large")]
+    fn stack_color_overflow() {
+        let _ = ThreadFuture::from(park).with_stack_color(usize::MAX);
+    }
+
+    #[test]
+    fn int_registers_only() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let thread = ThreadFuture::from(|| {
+            let mut a: u64 = 1;
+            let mut b: u64 = 1;
+            for _ in 0..50 {
+                let c = a.wrapping_add(b);
+                a = b;
+                b = c;
+                yield_now();
+            }
+            b
+        });
+        // the thread does integer arithmetic only
+        let mut thread = Box::new(unsafe { thread.with_registers(RegSet::INT) });
+        let ret = loop {
+            if let Poll::Ready(ret) = thread.poll_unpin(&mut cx) {
+                break ret;
+            }
+        };
+        assert_eq!(ret, 32951280099);
+    }
+
+    #[tokio::test]
+    async fn take_waker() {
+        let slot = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let reactor = slot.clone();
+        let thread = tokio::spawn(ThreadFuture::from(move || {
+            let waker = take_current_waker().unwrap();
+            assert!(take_current_waker().is_none());
+            *reactor.lock().unwrap() = Some(waker);
+            // only the taken waker can resume it
+            park();
+            // stored again by the poll
+            current_waker();
+        }));
+        let waker = loop {
+            if let Some(waker) = slot.lock().unwrap().take() {
+                break waker;
+            }
+            tokio::task::yield_now().await;
+        };
+        waker.wake();
+        thread.await.unwrap();
+    }
+
+    #[cfg(feature = "hints")]
+    #[test]
+    fn post_yield_hook_kinds() {
+        type Record = (ThreadId, YieldKind, YieldReason);
+        static KINDS: SpinLock<Vec<Record>> = SpinLock::new(Vec::new());
+        fn hook(id: ThreadId, kind: YieldKind, reason: YieldReason) {
+            KINDS.lock().push((id, kind, reason));
+        }
+        set_post_yield_hook(hook);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| {
+            yield_now();
+            yield_with_reason(YieldReason::Io);
+            park();
+        }));
+        let id = thread.id();
+        // a dropped thread of another test may have had the same address
+        KINDS.lock().retain(|(tid, _, _)| *tid != id);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.yield_reason(), YieldReason::Io);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(()));
+        // other tests run threads concurrently
+        let kinds: Vec<_> = KINDS
+            .lock()
+            .iter()
+            .filter(|(tid, _, _)| *tid == id)
+            .map(|&(_, kind, reason)| (kind, reason))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (YieldKind::Yield, YieldReason::Voluntary),
+                (YieldKind::Yield, YieldReason::Io),
+                (YieldKind::Park, YieldReason::Wait),
+                (YieldKind::Exit, YieldReason::Voluntary),
+            ]
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn direction_flag_cleared_on_switch() {
+        const DF: usize = 1 << 10;
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| {
+            unsafe { asm!("std" :::: "volatile") };
+            yield_now();
+        }));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let rflags: usize;
+        unsafe { asm!("pushfq; pop $0" : "=r"(rflags) ::: "volatile" "intel") };
+        assert_eq!(rflags & DF, 0);
+        // a forward copy works
+        let src: Vec<u8> = (0..=255).collect();
+        let mut dst = vec![0u8; src.len()];
+        dst.copy_from_slice(&src);
+        assert_eq!(dst, src);
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(()));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn vector_control_is_per_thread() {
+        // round toward zero
+        const RC_ZERO: u32 = 0x6000;
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| unsafe {
+            let mut ext = ExtContext::default();
+            ext.save(RegSet::VECTOR);
+            ext.mxcsr |= RC_ZERO;
+            asm!("ldmxcsr [$0]" :: "r"(&ext.mxcsr) :: "volatile" "intel");
+            yield_now();
+            ext.save(RegSet::VECTOR);
+            ext.mxcsr
+        }));
+        let mut before = ExtContext::default();
+        unsafe { before.save(RegSet::VECTOR) };
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let mut after = ExtContext::default();
+        unsafe { after.save(RegSet::VECTOR) };
+        assert_eq!(after.mxcsr, before.mxcsr);
+        match thread.poll_unpin(&mut cx) {
+            Poll::Ready(mxcsr) => assert_eq!(mxcsr & RC_ZERO, RC_ZERO),
+            Poll::Pending => panic!("thread not exited"),
+        }
+    }
+
+    #[test]
+    fn fp_registers_survive_yield() {
+        // the accumulators live across the calls, so they are kept in
+        // callee-saved FP registers where the ABI has them
+        #[inline(never)]
+        fn mix(pause: fn()) -> (f64, f64) {
+            let (mut a, mut b) = (1.5f64, -0.25f64);
+            for i in 0..8 {
+                pause();
+                a = a * 1.25 + b;
+                b = b * 0.5 - i as f64;
+            }
+            (a, b)
+        }
+        fn nop() {}
+        let expected = mix(nop);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| mix(yield_now)));
+        // the executor keeps its own FP values across the polls
+        let (mut x, mut y) = (3.0f64, 0.75f64);
+        let mut polls = 0;
+        let got = loop {
+            if let Poll::Ready(got) = thread.poll_unpin(&mut cx) {
+                break got;
+            }
+            polls += 1;
+            x = x * 0.5 + y;
+            y = y * 1.5 - x;
+        };
+        assert_eq!(polls, 8);
+        assert_eq!(got, expected);
+        let (mut x2, mut y2) = (3.0f64, 0.75f64);
+        for _ in 0..polls {
+            x2 = x2 * 0.5 + y2;
+            y2 = y2 * 1.5 - x2;
+        }
+        assert_eq!((x, y), (x2, y2));
+    }
+
+    #[test]
+    #[should_panic(expected = "reset a thread which is parked or running")]
+    fn reset_parked_thread() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(park as fn()));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        thread.reset(park);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn reset_after_panic() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Guard;
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        fn boom() -> u32 {
+            let _guard = Guard;
+            yield_now();
+            panic!("boom");
+        }
+        fn one() -> u32 {
+            1
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(boom as fn() -> u32).with_name("reset"));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(!thread.was_panicked());
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = thread.poll_unpin(&mut cx);
+        }));
+        assert!(panicked.is_err());
+        assert!(thread.was_panicked());
+        // the stack was unwound before the panic reached here
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+
+        thread.reset(one);
+        assert!(!thread.was_panicked());
+        assert_eq!(thread.name(), Some("reset"));
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(1));
+    }
+
+    #[cfg(feature = "hints")]
+    #[test]
+    fn deadline() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(
+            ThreadFuture::from(|| {
+                assert_eq!(current_deadline(), Some(100));
+                set_deadline(Some(200));
+                yield_now();
+                set_deadline(None);
+                park();
+            })
+            .with_deadline(100),
+        );
+        assert_eq!(thread.deadline(), Some(100));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.deadline(), Some(200));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.deadline(), None);
+    }
+
+    #[cfg(feature = "hints")]
+    #[test]
+    fn run_class_hint() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| {
+            hint_duration(RunClass::Short);
+            yield_now();
+            hint_duration(RunClass::Long);
+            park();
+        }));
+        assert_eq!(thread.run_class(), RunClass::Medium);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.run_class(), RunClass::Short);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.run_class(), RunClass::Long);
+    }
+
+    #[tokio::test]
+    async fn select2_first_exited() {
+        let yield_once = || {
+            yield_now();
+            1u32
+        };
+        let park_forever = || loop {
+            park();
+        };
+        let h1 = tokio::spawn(select2(
+            ThreadFuture::from(yield_once),
+            ThreadFuture::from(park_forever),
+        ));
+        let h2 = tokio::spawn(select2(
+            ThreadFuture::from(park_forever),
+            ThreadFuture::from(yield_once),
+        ));
+        assert_eq!(h1.await.unwrap(), Either::Left(1));
+        assert_eq!(h2.await.unwrap(), Either::<(), _>::Right(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "moved after being polled")]
+    fn poll_after_move() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(park));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let mut moved = Box::new(*thread);
+        let _ = moved.poll_unpin(&mut cx);
+    }
+
+    #[test]
+    #[should_panic(expected = "polled after completion")]
+    fn poll_after_completion() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = ThreadFuture::from(|| {});
+        assert!(thread.poll_unpin(&mut cx).is_ready());
+        let _ = thread.poll_unpin(&mut cx);
+    }
+
+    /// The high-level API needs no `unsafe` from the caller.
+    #[forbid(unsafe_code)]
+    mod safe_api {
+        use crate::*;
+
+        #[tokio::test]
+        async fn safe_api() {
+            let ret = ThreadFuture::from(|| {
+                let waker = current_waker();
                 waker.wake();
+                park();
+                yield_now();
+                1u32
+            })
+            .await;
+            assert_eq!(ret, 1);
+            assert_eq!(block_on(ThreadFuture::from(|| 2u32)), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn inspect() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let ret = ThreadFuture::from(|| {
+            yield_now();
+            7u32
+        })
+        .inspect(|&ret| {
+            assert_eq!(ret, 7);
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        })
+        .await;
+        assert_eq!(ret, 7);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn return_not_unpin() {
+        struct Node {
+            value: u32,
+            _pin: core::marker::PhantomPinned,
+        }
+        let thread = ThreadFuture::from(|| {
+            yield_now();
+            Node {
+                value: 1,
+                _pin: core::marker::PhantomPinned,
+            }
+        });
+        let node = Box::pin(thread).await;
+        assert_eq!(node.value, 1);
+    }
+
+    #[tokio::test]
+    async fn in_green_thread() {
+        assert!(!super::in_green_thread());
+        assert!(ThreadFuture::from(super::in_green_thread).await);
+    }
+
+    #[test]
+    fn poll_n() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = ThreadFuture::from(|| {
+            for _ in 0..5 {
+                yield_now();
+            }
+            1u32
+        });
+        assert_eq!(thread.poll_n(&mut cx, 8), Poll::Ready(1));
+
+        let mut thread = ThreadFuture::from(|| {
+            yield_now();
+            park();
+        });
+        assert!(thread.poll_n(&mut cx, 8).is_pending());
+        // stopped at `park` after two switches
+        assert_eq!(thread.poll_n(&mut cx, 8), Poll::Ready(()));
+    }
+
+    #[test]
+    fn yield_budget() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let yield_three_times = || {
+            for _ in 0..3 {
+                yield_now();
+            }
+            1u32
+        };
+        let mut thread = ThreadFuture::from(yield_three_times).with_yield_budget(4);
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(1));
+
+        let mut thread = ThreadFuture::from(yield_three_times).with_yield_budget(2);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        // the budget is refilled, and one yield is left
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(1));
+    }
+
+    #[test]
+    fn stack_poison() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let use_stack = || {
+            let mut buf = [0u8; 1024];
+            for x in buf.iter_mut() {
+                unsafe { core::ptr::write_volatile(x, 1) };
+            }
+            yield_now();
+        };
+        let mut thread = Box::new(ThreadFuture::from(use_stack).with_stack_poison());
+        assert_eq!(thread.stack_used_bytes(), None);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let used = thread.stack_used_bytes().unwrap();
+        let usable = stack_layout::<fn(), ()>().usable_bytes;
+        assert!(used >= 1024 && used < usable, "used {} bytes", used);
+        assert!(thread.poll_unpin(&mut cx).is_ready());
+        // the high-water never goes down
+        assert!(thread.stack_used_bytes().unwrap() >= used);
+
+        let mut thread = Box::new(ThreadFuture::from(use_stack));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.stack_used_bytes(), None);
+    }
+
+    #[test]
+    fn fuse() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = ThreadFuture::from(|| {
+            yield_now();
+            1u32
+        })
+        .fuse();
+        assert!(Pin::new(&mut thread).poll(&mut cx).is_pending());
+        assert!(!thread.is_terminated());
+        assert_eq!(Pin::new(&mut thread).poll(&mut cx), Poll::Ready(1));
+        assert!(thread.is_terminated());
+        for _ in 0..3 {
+            assert_eq!(Pin::new(&mut thread).poll(&mut cx), Poll::Pending);
+        }
+    }
+
+    #[test]
+    fn entry_receives_arg0() {
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+        // `arg` points to the context pointer of the caller
+        unsafe extern "C" fn record(arg: usize) {
+            SEEN.store(arg, Ordering::SeqCst);
+            ThreadContext::switch(arg as *mut *mut ThreadContext);
+            unreachable!();
+        }
+
+        #[repr(align(16))]
+        struct Stack([u8; 0x1000]);
+        let mut stack = Box::new(Stack([0; 0x1000]));
+        let top = stack.0.as_mut_ptr() as usize + 0x1000 - STACK_TOP_RESERVED;
+        let mut context = unsafe { (top as *mut ThreadContext).sub(1) };
+        let arg = &mut context as *mut _ as usize;
+        unsafe {
+            (*context).set_entry(record as usize, arg);
+            ThreadContext::switch(&mut context);
+        }
+        assert_eq!(SEEN.load(Ordering::SeqCst), arg);
+    }
+
+    #[cfg(feature = "overflow-canary")]
+    #[test]
+    #[should_panic(expected = "canary is changed")]
+    fn detect_top_canary() {
+        let mut thread = Box::new(ThreadFuture::from(yield_now));
+        thread.check_stack();
+        unsafe {
+            (*thread.tcb).canary = 0;
+        }
+        thread.check_stack();
+    }
+
+    #[cfg(all(feature = "overflow-canary", feature = "hints"))]
+    #[test]
+    fn without_canary_check_skips_and_restores() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| unsafe {
+            let tcb = TCB::<fn(), ()>::current_unchecked();
+            let canary = (tcb as *mut _ as usize + tcb.canary_offset) as *mut usize;
+            without_canary_check(|| {
+                *canary = 0;
+                // both check the canary unless it is disabled
+                yield_now();
+                hint_duration(RunClass::Short);
+                *canary = CANARY;
             });
+            assert!(!tcb.skip_canary);
+            *canary = 0;
+            let panicked = std::panic::catch_unwind(|| hint_duration(RunClass::Long));
+            *canary = CANARY;
+            assert!(panicked.is_err());
+        }));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(()));
+        assert_eq!(thread.run_class(), RunClass::Short);
+    }
+
+    #[test]
+    fn child_yields_to_parent() {
+        static LOG: SpinLock<Vec<&str>> = SpinLock::new(Vec::new());
+        let log = |step| LOG.lock().push(step);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut parent = Box::new(
+            ThreadFuture::from(move || {
+                let mut child = Box::new(
+                    ThreadFuture::from(move || {
+                        assert_eq!(current_thread_name(), Some("child"));
+                        log("child runs");
+                        yield_now();
+                        log("child resumes");
+                        7u32
+                    })
+                    .with_name("child"),
+                );
+                let waker = current_waker();
+                let mut cx = Context::from_waker(&waker);
+                log("parent polls child");
+                assert_eq!(child.poll_unpin(&mut cx), Poll::Pending);
+                // the yield of the child came back here, not to the test
+                assert_eq!(current_thread_name(), Some("parent"));
+                log("parent yields");
+                yield_now();
+                log("parent polls child");
+                assert_eq!(child.poll_unpin(&mut cx), Poll::Ready(7));
+                assert_eq!(current_thread_name(), Some("parent"));
+                7
+            })
+            .with_name("parent"),
+        );
+        assert!(parent.poll_unpin(&mut cx).is_pending());
+        assert_eq!(
+            *LOG.lock(),
+            ["parent polls child", "child runs", "parent yields"]
+        );
+        assert_eq!(parent.poll_unpin(&mut cx), Poll::Ready(7));
+        assert_eq!(
+            *LOG.lock(),
+            [
+                "parent polls child",
+                "child runs",
+                "parent yields",
+                "parent polls child",
+                "child resumes"
+            ]
+        );
+    }
+
+    #[test]
+    fn poll_detailed_tells_yield_from_park() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(|| {
+            yield_now();
+            park();
+            3u32
+        }));
+        let mut poll = || Pin::new(&mut *thread).poll_detailed(&mut cx);
+        assert_eq!(poll(), DetailedPoll::Yielded);
+        assert_eq!(poll(), DetailedPoll::Parked);
+        assert_eq!(poll(), DetailedPoll::Ready(3));
+    }
+
+    #[cfg(feature = "overflow-canary")]
+    #[test]
+    #[should_panic(expected = "maybe stack overflow! thread: Some(\"victim\")")]
+    fn canary_panic_names_thread() {
+        let mut thread = Box::new(ThreadFuture::from(yield_now).with_name("victim"));
+        unsafe {
+            (*thread.tcb).canary = 0;
+        }
+        thread.check_stack();
+    }
+
+    /// Logger shared by tests, since only one can be set.
+    struct Logger {
+        overflow: SpinLock<Option<StackOverflowInfo>>,
+        #[cfg_attr(not(feature = "dangling-check"), allow(dead_code))]
+        dangling: SpinLock<Option<DanglingReturnInfo>>,
+        #[cfg_attr(not(debug_assertions), allow(dead_code))]
+        dropped: SpinLock<Option<DroppedThreadInfo>>,
+    }
+
+    impl OverflowLogger for Logger {
+        fn report(&self, info: &StackOverflowInfo) {
+            // other tests may report too
+            if info.name == Some("overflow_logger") {
+                *self.overflow.lock() = Some(*info);
+            }
+        }
+
+        fn report_dangling(&self, info: &DanglingReturnInfo) {
+            if info.name == Some("dangling_return") {
+                *self.dangling.lock() = Some(*info);
+            }
+        }
+
+        fn report_dropped(&self, info: &DroppedThreadInfo) {
+            if info.name == Some("dropped_parked") {
+                *self.dropped.lock() = Some(*info);
+            }
+        }
+    }
+
+    #[cfg_attr(
+        not(any(
+            feature = "overflow-canary",
+            feature = "dangling-check",
+            debug_assertions
+        )),
+        allow(dead_code)
+    )]
+    static LOGGER: Logger = Logger {
+        overflow: SpinLock::new(None),
+        dangling: SpinLock::new(None),
+        dropped: SpinLock::new(None),
+    };
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn dropped_parked() {
+        set_overflow_logger(&LOGGER);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(park).with_name("dropped_parked"));
+        // not polled yet, so nothing is leaked
+        drop(Box::new(ThreadFuture::from(park).with_name("dropped_parked")));
+        assert_eq!(*LOGGER.dropped.lock(), None);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let start = &*thread as *const _ as usize;
+        drop(thread);
+        let info = LOGGER.dropped.lock().take().expect("no warning");
+        assert_eq!(info.name, Some("dropped_parked"));
+        assert_eq!(info.start, start);
+    }
+
+    #[cfg(all(debug_assertions, feature = "dangling-check"))]
+    #[test]
+    fn dangling_return() {
+        set_overflow_logger(&LOGGER);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(
+            ThreadFuture::from(|| {
+                let local = 1u32;
+                &local as *const u32 as usize
+            })
+            .with_name("dangling_return"),
+        );
+        let start = &*thread as *const _ as usize;
+        let value = match thread.poll_unpin(&mut cx) {
+            Poll::Ready(value) => value,
+            Poll::Pending => unreachable!(),
+        };
+        let info = LOGGER.dangling.lock().take().unwrap();
+        assert_eq!(
+            info,
+            DanglingReturnInfo {
+                name: Some("dangling_return"),
+                start,
+                value,
+            }
+        );
+    }
+
+    #[cfg(feature = "overflow-canary")]
+    #[test]
+    fn overflow_logger() {
+        set_overflow_logger(&LOGGER);
+
+        let mut thread = Box::new(ThreadFuture::from(yield_now).with_name("overflow_logger"));
+        unsafe {
+            (*thread.tcb).canary = 0;
+        }
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            thread.check_stack();
+        }));
+        assert!(panicked.is_err());
+        let (stack_base, stack_size) = unsafe { thread.tcb.stack_region() };
+        let info = LOGGER.overflow.lock().take().unwrap();
+        assert_eq!(
+            info,
+            StackOverflowInfo {
+                name: Some("overflow_logger"),
+                stack_base,
+                stack_size,
+                canary_addr: unsafe { &thread.tcb.canary as *const usize as usize },
+                expected: CANARY,
+                found: 0,
+            }
+        );
+    }
+
+    #[cfg(feature = "overflow-canary")]
+    #[test]
+    fn stack_fault_handler() {
+        static FOUND: AtomicUsize = AtomicUsize::new(0);
+        fn handler(info: StackOverflowInfo) -> ! {
+            if info.name == Some("stack_fault_handler") {
+                FOUND.store(info.found, Ordering::SeqCst);
+            }
+            // other tests expect the default panic message
+            // the top canary is the last word before the stack
+            if info.canary_addr + core::mem::size_of::<usize>() != info.stack_base {
+                panic!("inner canary is changed. maybe stack overflow!");
+            }
+            panic!("canary is changed. maybe stack overflow!");
+        }
+        set_stack_fault_handler(handler);
+
+        let mut thread = Box::new(ThreadFuture::from(yield_now).with_name("stack_fault_handler"));
+        unsafe {
+            (*thread.tcb).canary = 0x1234;
+        }
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            thread.check_stack();
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(FOUND.load(Ordering::SeqCst), 0x1234);
+    }
+
+    #[cfg(feature = "overflow-canary")]
+    #[test]
+    #[should_panic(expected = "canary is changed")]
+    fn overflow_stops_at_canary() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(park));
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let (base, _) = unsafe { thread.tcb.stack_region() };
+        let context_ptr = unsafe { thread.tcb.context_ptr };
+        // overflow the stack by one word
+        let below = (base - core::mem::size_of::<usize>()) as *mut usize;
+        assert_eq!(below as usize, unsafe {
+            &thread.tcb.canary as *const _ as usize
+        });
+        unsafe {
+            *below = 0;
+        }
+        assert_eq!(unsafe { thread.tcb.context_ptr }, context_ptr);
+        assert!(unsafe { thread.tcb.waker.is_some() });
+        thread.check_stack();
+    }
+
+    #[cfg(feature = "double-canary")]
+    #[test]
+    #[should_panic(expected = "inner canary is changed")]
+    fn detect_inner_canary() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(yield_now));
+        assert!(Pin::new(&mut *thread).poll(&mut cx).is_pending());
+        thread.check_stack();
+        // a sparse overflow past the top canary
+        unsafe {
+            (*thread.tcb).inner_canary = 0;
+        }
+        thread.check_stack();
+    }
+
+    #[tokio::test]
+    async fn detached_thread() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let thread: DetachedThread<_> = ThreadFuture::from(|| {
+            for _ in 0..3 {
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+                yield_now();
+            }
+            let waker = current_waker();
+            tokio::spawn(async move { waker.wake() });
             park();
+        });
+        tokio::spawn(thread).await.unwrap();
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn stack_layout_of_trivial_closure() {
+        const LAYOUT: StackLayout = stack_layout::<fn(), ()>();
+        assert_eq!(
+            LAYOUT.usable_bytes + LAYOUT.tcb_bytes + LAYOUT.context_bytes,
+            RAW_SIZE
+        );
+        assert_eq!(LAYOUT.tcb_bytes, core::mem::size_of::<TCB<fn(), ()>>());
+        // the TCB must leave almost all of the region to the stack
+        assert!(LAYOUT.tcb_bytes <= RAW_SIZE / 16, "TCB takes {} bytes", LAYOUT.tcb_bytes);
+        if !cfg!(feature = "hints") {
+            assert_eq!(core::mem::size_of::<Hints>(), 1);
+        }
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(LAYOUT.context_bytes, 8 * 8 + 8);
+    }
+
+    #[test]
+    fn max_frames_of_trivial_closure() {
+        const FRAMES: usize = max_frames::<fn(), ()>(128);
+        assert_eq!(FRAMES, stack_layout::<fn(), ()>().usable_bytes / 128);
+        // (0x2000 - TCB - context) / 128
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            FRAMES,
+            (RAW_SIZE - stack_layout::<fn(), ()>().tcb_bytes - (8 * 8 + 8)) / 128
+        );
+    }
+
+    #[tokio::test]
+    async fn try_clone() {
+        let counter = std::sync::Arc::new(AtomicUsize::new(0));
+        let c = counter.clone();
+        let thread = ThreadFuture::from(move || {
+            yield_now();
+            c.fetch_add(1, Ordering::SeqCst) + 1
+        })
+        .with_name("template");
+        let copy = thread.try_clone().unwrap();
+        assert_eq!(copy.name(), Some("template"));
+        let mut rets = [thread.await, copy.await];
+        rets.sort();
+        assert_eq!(rets, [1, 2]);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn try_clone_started() {
+        let mut thread = ThreadFuture::from(park);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(thread.try_clone().is_none());
+    }
+
+    #[test]
+    fn from_raw() {
+        use std::alloc::{alloc, dealloc, Layout};
+        let layout = Layout::from_size_align(RAW_SIZE, RAW_SIZE).unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        unsafe {
+            let ptr = NonNull::new(alloc(layout)).unwrap();
+            let thread = ThreadFuture::from_raw(ptr, || {
+                yield_now();
+                1u32
+            });
+            assert_eq!(thread as *mut _ as *mut u8, ptr.as_ptr());
+            assert!(thread.poll_unpin(&mut cx).is_pending());
+            assert_eq!(thread.poll_unpin(&mut cx), Poll::Ready(1));
+            core::ptr::drop_in_place(thread);
+            dealloc(ptr.as_ptr(), layout);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "re-entrant poll")]
+    fn reentrant_poll() {
+        static THIS: AtomicUsize = AtomicUsize::new(0);
+        /// Poll the running thread from inside itself.
+        fn poll_self() {
+            let this = THIS.load(Ordering::SeqCst) as *mut ThreadFuture<fn(), ()>;
+            let waker = noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            let _ = unsafe { (*this).poll_unpin(&mut cx) };
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = Box::new(ThreadFuture::from(poll_self as fn()));
+        THIS.store(&mut *thread as *mut _ as usize, Ordering::SeqCst);
+        // the panic on the green stack is resumed here
+        let _ = thread.poll_unpin(&mut cx);
+    }
+
+    #[test]
+    fn backtrace_at_entry() {
+        /// Symbol addresses and stack pointers of the frames, innermost first,
+        /// and the top of the stack.
+        fn walk() -> (Vec<(usize, usize)>, usize) {
+            let mut frames = Vec::new();
+            backtrace::trace(|frame| {
+                frames.push((frame.symbol_address() as usize, frame.sp() as usize));
+                frames.len() < 100
+            });
+            let top = (&frames as *const _ as usize & !(RAW_SIZE - 1)) + RAW_SIZE;
+            (frames, top)
+        }
+        type Walk = fn() -> (Vec<(usize, usize)>, usize);
+
+        // the frame pointer the thread starts with is null
+        let mut stack = [0usize; 64];
+        let top = stack.as_mut_ptr() as usize + core::mem::size_of_val(&stack);
+        let context = unsafe { ThreadContext::init(top & !0xf, 0x1230, 0) };
+        let fp = unsafe { *((context as usize + layout::CONTEXT_FP) as *const usize) };
+        assert_eq!(fp, 0);
+
+        let (frames, top) = block_on(ThreadFuture::from(walk as Walk));
+        assert!(frames.len() < 100, "the walk does not terminate");
+        let &(outermost, sp) = frames.last().unwrap();
+        assert_eq!(
+            outermost,
+            entry::<Walk, (Vec<(usize, usize)>, usize), YieldOnExit> as usize,
+            "the walk does not end at `entry`"
+        );
+        // it stops above `entry`, on the null return address and frame pointer
+        // left by `ThreadContext::init`, rather than running off the stack
+        assert!((top - RAW_SIZE..top).contains(&sp));
+    }
+
+    #[test]
+    fn aligned_local_after_resume() {
+        #[repr(align(16))]
+        struct Aligned(u8);
+
+        let (addr, _) = block_on(ThreadFuture::from(|| {
+            yield_now();
+            let local = Aligned(0);
+            (&local as *const _ as usize, local.0)
+        }));
+        assert_eq!(addr % 16, 0);
+    }
+
+    #[cfg(target_arch = "x86")]
+    #[test]
+    fn x86_smoke() {
+        // ebx, esi, edi, ebp, eip and padding, then the fake return address and argument
+        assert_eq!(stack_layout::<fn(), ()>().context_bytes, 32 + 20);
+        let ret = block_on(
+            ThreadFuture::from(|| {
+                yield_now();
+                current_thread_name()
+            })
+            .with_name("x86"),
+        );
+        assert_eq!(ret, Some("x86"));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[tokio::test]
+    async fn sse_aligned_store_after_switch() {
+        #[repr(align(16))]
+        struct Aligned([u8; 16]);
+
+        let h1 = tokio::spawn(ThreadFuture::from(|| {
+            yield_now();
+            let mut buf = Aligned([0; 16]);
+            // `movaps` faults if the address is not 16-byte aligned
+            unsafe {
+                asm!("movaps [$0], xmm0" :: "r"(&mut buf) : "memory" : "volatile" "intel");
+            }
+            buf.0
         }));
         h1.await.unwrap();
     }
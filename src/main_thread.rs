@@ -0,0 +1,157 @@
+//! The boot context adopted as a green thread.
+
+use crate::{
+    in_green_thread, stack_pointer, State, ThreadFuture, ThreadName, RAW_SIZE, STACK_BYTES, TCB,
+};
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+use core::task::{Context, Poll};
+
+/// The current context adopted as a green thread by [`adopt_current_stack`].
+///
+/// While it lives, code on this stack sees a green thread: it has a name,
+/// green TLS slots and [`in_green_thread`] is `true`. Other threads are run
+/// from it by [`switch_to`](MainThreadHandle::switch_to), which returns when
+/// they switch back, so an idle loop is a task like the others.
+///
+/// The main thread is not a future, so it never switches out by itself:
+/// [`yield_now`](crate::yield_now) and [`park`](crate::park) on it panic.
+///
+/// Dropping the handle releases the TCB, and the stack is plain again.
+pub struct MainThreadHandle {
+    tcb: &'static mut TCB<fn(), ()>,
+    /// The TCB is on the stack of this OS thread or CPU.
+    _not_send: PhantomData<*mut ()>,
+}
+
+/// Adopt the current stack as a green thread, with a TCB built in place.
+///
+/// The TCB is written at the 0x2000-aligned base of the live stack, like the
+/// TCB at the base of a thread allocated by [`ThreadFuture`]. Nothing is
+/// allocated. Panics if the current code is already in a green thread, or if
+/// the stack has grown into where the TCB goes.
+///
+/// # Safety
+///
+/// - The current stack must be a 0x2000-aligned region of 0x2000 bytes,
+///   such as a boot stack placed by the linker script, whose lowest bytes
+///   are not used by anything else.
+/// - The stack must not grow into the TCB while the handle lives.
+///   The canary catches it, if the `overflow-canary` feature is enabled.
+pub unsafe fn adopt_current_stack() -> MainThreadHandle {
+    assert!(!in_green_thread(), "already in a green thread");
+    let sp = stack_pointer();
+    let base = sp & !(RAW_SIZE - 1);
+    let tcb_size = core::mem::size_of::<TCB<fn(), ()>>();
+    assert!(
+        sp - base > tcb_size,
+        "the stack is too deep to adopt: the TCB would clobber live frames"
+    );
+    let mut tcb = TCB::<fn(), ()>::new(|| {});
+    // the stack is not allocated by us
+    STACK_BYTES.fetch_sub(RAW_SIZE, Ordering::Relaxed);
+    tcb.state = State::Running;
+    tcb.on_cpu = true;
+    let ptr = base as *mut TCB<fn(), ()>;
+    ptr.write(tcb);
+    MainThreadHandle {
+        tcb: &mut *ptr,
+        _not_send: PhantomData,
+    }
+}
+
+impl MainThreadHandle {
+    /// Set the name of the main thread, read by
+    /// [`current_thread_name`](crate::current_thread_name).
+    pub fn with_name(self, name: &'static str) -> Self {
+        self.tcb.name = ThreadName::new(name);
+        self
+    }
+
+    /// Switch from the main thread into `thread` until it switches back.
+    ///
+    /// It is a poll of `thread`, with the main thread marked off CPU meanwhile.
+    pub fn switch_to<F, T>(
+        &mut self,
+        thread: &mut ThreadFuture<F, T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<T>
+    where
+        F: Send + 'static + Unpin + FnOnce() -> T,
+        T: Send + 'static + Unpin,
+    {
+        self.tcb.check_canary();
+        self.tcb.on_cpu = false;
+        let ret = thread.poll_unpin(cx);
+        self.tcb.on_cpu = true;
+        self.tcb.check_canary();
+        ret
+    }
+}
+
+impl Drop for MainThreadHandle {
+    fn drop(&mut self) {
+        self.tcb.check_canary();
+        let ptr = self.tcb as *mut TCB<fn(), ()>;
+        unsafe {
+            core::ptr::drop_in_place(ptr);
+            // no canary is left for `in_green_thread` to find
+            core::ptr::write_bytes(ptr as *mut u8, 0, core::mem::size_of::<TCB<fn(), ()>>());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::noop_waker;
+    use crate::{current_thread_name, yield_now};
+
+    /// Grow the stack until the stack pointer is near the top of a 0x2000
+    /// window, so that there is room below it for the TCB and frames.
+    #[inline(never)]
+    fn at_window_top(f: &mut dyn FnMut()) {
+        let pad = [0u8; 256];
+        if stack_pointer() & (RAW_SIZE - 1) < 0x1800 {
+            at_window_top(f);
+        } else {
+            f();
+        }
+        unsafe { core::ptr::read_volatile(&pad[255]) };
+    }
+
+    #[test]
+    fn switch_into_thread_and_back() {
+        let mut thread = Box::new(
+            ThreadFuture::from(|| {
+                assert_eq!(current_thread_name(), Some("worker"));
+                yield_now();
+                assert_eq!(current_thread_name(), Some("worker"));
+                1u32
+            })
+            .with_name("worker"),
+        );
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        at_window_top(&mut || {
+            assert!(!in_green_thread());
+            let mut main = unsafe { adopt_current_stack() }.with_name("main");
+            assert!(in_green_thread());
+            assert_eq!(current_thread_name(), Some("main"));
+            assert_eq!(main.switch_to(&mut *thread, &mut cx), Poll::Pending);
+            assert_eq!(current_thread_name(), Some("main"));
+            assert_eq!(main.switch_to(&mut *thread, &mut cx), Poll::Ready(1));
+            drop(main);
+            assert!(!in_green_thread());
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "the main thread cannot switch out")]
+    fn yield_on_main_thread() {
+        at_window_top(&mut || {
+            let _main = unsafe { adopt_current_stack() };
+            yield_now();
+        });
+    }
+}
@@ -0,0 +1,208 @@
+//! Child threads driven by a parent green thread.
+
+use crate::{current_waker, park, PlacedThread};
+#[cfg(feature = "std")]
+use crate::{ThreadFuture, RAW_SIZE};
+use core::future::Future;
+use core::pin::Pin;
+#[cfg(feature = "std")]
+use core::ptr::NonNull;
+use core::task::{Context, Poll};
+#[cfg(feature = "std")]
+use std::alloc::{alloc, dealloc, Layout};
+
+/// Handle of a child thread created by [`spawn_child`].
+pub struct ChildHandle<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    thread: PlacedThread<F, T>,
+    ret: Option<T>,
+}
+
+/// Create a child thread running `f` in `region`, to be joined by [`join_all`].
+///
+/// The child does not run until the parent joins it.
+/// See [`PlacedThread::new`] for the requirement of `region`.
+pub fn spawn_child<F, T>(region: &'static mut [u8], f: F) -> ChildHandle<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    ChildHandle {
+        thread: PlacedThread::new(region, f),
+        ret: None,
+    }
+}
+
+impl<F, T> ChildHandle<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    /// Whether the child has exited.
+    pub fn is_finished(&self) -> bool {
+        self.ret.is_some()
+    }
+
+    /// Take the return value of the child if it has exited.
+    pub fn take(&mut self) -> Option<T> {
+        self.ret.take()
+    }
+}
+
+/// A child thread which can be joined by [`join_all`], whatever its closure
+/// and return types.
+pub trait Joinable {
+    /// Poll the child once, and keep its return value if it exits.
+    ///
+    /// Returns whether it has exited.
+    fn poll_join(&mut self, cx: &mut Context<'_>) -> bool;
+}
+
+impl<F, T> Joinable for ChildHandle<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    fn poll_join(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.is_finished() {
+            return true;
+        }
+        match Pin::new(&mut self.thread).poll(cx) {
+            Poll::Ready(ret) => {
+                self.ret = Some(ret);
+                true
+            }
+            Poll::Pending => false,
+        }
+    }
+}
+
+/// Block the current thread until all `children` have exited.
+///
+/// The children are not run by the executor on their own: they are polled
+/// from the stack of the current thread with its waker, so they run inside
+/// the task of the parent, one at a time, and never in parallel with it or
+/// with each other. A child which yields or is woken wakes the parent, which
+/// polls the unfinished children again.
+///
+/// Must be called inside a green thread.
+pub fn join_all(children: &mut [&mut dyn Joinable]) {
+    let waker = current_waker();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        let mut done = true;
+        for child in children.iter_mut() {
+            done &= child.poll_join(&mut cx);
+        }
+        if done {
+            return;
+        }
+        park();
+    }
+}
+
+/// Run `f` on a fresh green thread and block the current thread until it exits.
+///
+/// Like `tokio::task::block_in_place`, it returns the value synchronously.
+/// The new thread is polled from the stack of the current thread with its
+/// waker, so when it yields or parks, the current thread parks too, and
+/// the executor gets the CPU back until the new thread is woken.
+///
+/// The new thread is allocated on the heap, since its 0x2000 bytes do not
+/// fit on the stack of the current thread.
+///
+/// Must be called inside a green thread.
+#[cfg(feature = "std")]
+pub fn block_in_place<F, T>(f: F) -> T
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    /// Frees the thread even if it panicked.
+    struct Transient<F, T> {
+        thread: NonNull<ThreadFuture<F, T>>,
+    }
+
+    impl<F, T> Drop for Transient<F, T> {
+        fn drop(&mut self) {
+            unsafe {
+                core::ptr::drop_in_place(self.thread.as_ptr());
+                dealloc(self.thread.as_ptr() as *mut u8, layout());
+            }
+        }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(RAW_SIZE, RAW_SIZE).unwrap()
+    }
+
+    let ptr = NonNull::new(unsafe { alloc(layout()) }).expect("out of memory");
+    // built in place, since a move would go through the current stack
+    let thread = unsafe { ThreadFuture::from_raw(ptr, f) };
+    let transient = Transient {
+        thread: NonNull::from(&mut *thread),
+    };
+    let waker = current_waker();
+    let mut cx = Context::from_waker(&waker);
+    let ret = loop {
+        if let Poll::Ready(ret) = thread.poll_unpin(&mut cx) {
+            break ret;
+        }
+        park();
+    };
+    drop(transient);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::placed_thread::tests::region;
+    use crate::{yield_now, ThreadFuture};
+
+    #[tokio::test]
+    async fn parent_joins_children() {
+        let ret = ThreadFuture::from(|| {
+            let mut a = spawn_child(region(), || {
+                yield_now();
+                2u32
+            });
+            // of another closure and return type
+            let mut b = spawn_child(region(), || {
+                yield_now();
+                yield_now();
+                "four"
+            });
+            join_all(&mut [&mut a, &mut b]);
+            (a.take().unwrap(), b.take().unwrap())
+        })
+        .await;
+        assert_eq!(ret, (2, "four"));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn block_in_place_yields_to_executor() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+        let ret = ThreadFuture::from(|| {
+            let inner = block_in_place(|| {
+                for _ in 0..3 {
+                    STEPS.fetch_add(1, Ordering::SeqCst);
+                    yield_now();
+                }
+                7u32
+            });
+            // the caller resumes on its own stack
+            yield_now();
+            inner + 1
+        })
+        .await;
+        assert_eq!(ret, 8);
+        assert_eq!(STEPS.load(Ordering::SeqCst), 3);
+    }
+}
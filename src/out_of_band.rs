@@ -0,0 +1,142 @@
+//! Return values stored out of the TCB.
+
+use crate::ThreadFuture;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Pointer to the slot of return value.
+struct SlotPtr<T>(*mut MaybeUninit<T>);
+
+unsafe impl<T: Send> Send for SlotPtr<T> {}
+
+impl<T> SlotPtr<T> {
+    /// Write the return value to the slot.
+    ///
+    /// # Safety
+    ///
+    /// The slot must be still borrowed by the [`OutOfBand`] driving this thread.
+    unsafe fn write(self, ret: T) {
+        (*self.0).as_mut_ptr().write(ret);
+    }
+
+    /// Get the address to construct the return value in place.
+    fn into_raw(self) -> *mut T {
+        self.0 as *mut T
+    }
+}
+
+/// Convert a closure of blocking thread to future, whose return value is
+/// written to `slot` instead of being stored in the TCB.
+///
+/// The TCB only holds the closure while the thread is alive, so a large `T`
+/// no longer takes space from the stack. The future outputs a reference to
+/// the value in `slot`.
+pub fn out_of_band<'a, F, T>(
+    f: F,
+    slot: &'a mut MaybeUninit<T>,
+) -> impl Future<Output = &'a mut T> + 'a
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static,
+{
+    let ptr = SlotPtr(slot as *mut _);
+    let thread = ThreadFuture::from(move || unsafe { ptr.write(f()) });
+    OutOfBand {
+        thread,
+        slot: Some(slot),
+    }
+}
+
+/// Like [`out_of_band`], but `f` constructs the return value in place.
+///
+/// `f` is given the address of `slot`, so the value is never built on the
+/// thread stack and moved out. This allows returning a value larger than
+/// the whole stack.
+///
+/// # Safety
+///
+/// `f` must fully initialize the value behind the pointer before returning.
+pub unsafe fn out_of_band_in_place<'a, F, T>(
+    f: F,
+    slot: &'a mut MaybeUninit<T>,
+) -> impl Future<Output = &'a mut T> + 'a
+where
+    F: Send + 'static + Unpin + FnOnce(*mut T),
+    T: Send + 'static,
+{
+    let ptr = SlotPtr(slot as *mut _);
+    let thread = ThreadFuture::from(move || f(ptr.into_raw()));
+    OutOfBand {
+        thread,
+        slot: Some(slot),
+    }
+}
+
+/// Future for [`out_of_band`] and [`out_of_band_in_place`].
+struct OutOfBand<'a, Th, T> {
+    thread: Th,
+    slot: Option<&'a mut MaybeUninit<T>>,
+}
+
+impl<'a, Th, T> Future for OutOfBand<'a, Th, T>
+where
+    Th: Future<Output = ()> + Unpin,
+{
+    type Output = &'a mut T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.thread).poll(cx) {
+            Poll::Ready(()) => {
+                let slot = this.slot.take().expect("polled after completion");
+                // the thread has written the slot before exit
+                Poll::Ready(unsafe { &mut *slot.as_mut_ptr() })
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yield_now;
+
+    #[tokio::test]
+    async fn large_return_value() {
+        let mut slot = MaybeUninit::uninit();
+        let ret: &mut [u64; 256] = out_of_band(
+            || {
+                yield_now();
+                let mut ret = [0; 256];
+                for (i, x) in ret.iter_mut().enumerate() {
+                    *x = i as u64;
+                }
+                ret
+            },
+            &mut slot,
+        )
+        .await;
+        assert!(ret.iter().enumerate().all(|(i, &x)| x == i as u64));
+    }
+
+    #[tokio::test]
+    async fn in_place_larger_than_stack() {
+        let mut slot = MaybeUninit::uninit();
+        let ret: &mut [u64; 1024] = unsafe {
+            out_of_band_in_place(
+                |ptr: *mut [u64; 1024]| {
+                    yield_now();
+                    for i in 0..1024 {
+                        (ptr as *mut u64).add(i).write(i as u64);
+                    }
+                },
+                &mut slot,
+            )
+        }
+        .await;
+        assert!(ret.iter().enumerate().all(|(i, &x)| x == i as u64));
+    }
+}
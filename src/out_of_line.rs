@@ -0,0 +1,101 @@
+//! Closures stored out of the TCB.
+
+use crate::ThreadFuture;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Pointer to the closure in its slot.
+struct ClosurePtr<F>(*mut F);
+
+unsafe impl<F: Send> Send for ClosurePtr<F> {}
+
+/// Convert a closure of blocking thread to future, storing the closure in
+/// `slot` instead of the TCB.
+///
+/// The TCB only holds a pointer, so a closure capturing a large buffer takes
+/// no space from the stack, and may even be larger than the whole thread.
+/// It is the capture-side analog of [`out_of_band`](crate::out_of_band).
+///
+/// The closure is called by reference, since calling an `FnOnce` would move
+/// it onto the stack. It is dropped in `slot` when the future is dropped.
+pub fn out_of_line<'a, F, T>(f: F, slot: &'a mut MaybeUninit<F>) -> impl Future<Output = T> + 'a
+where
+    F: Send + 'static + FnMut() -> T,
+    T: Send + 'static + Unpin,
+{
+    let ptr = ClosurePtr(slot.as_mut_ptr());
+    unsafe {
+        ptr.0.write(f);
+    }
+    // the closure outlives the thread, which is dropped first
+    let thread = ThreadFuture::from(move || unsafe { (*ptr.0)() });
+    OutOfLine {
+        thread: Some(thread),
+        slot,
+    }
+}
+
+/// Future for [`out_of_line`].
+struct OutOfLine<'a, Th, F> {
+    thread: Option<Th>,
+    slot: &'a mut MaybeUninit<F>,
+}
+
+impl<'a, Th, F> Future for OutOfLine<'a, Th, F>
+where
+    Th: Future + Unpin,
+{
+    type Output = Th::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let thread = self.get_mut().thread.as_mut().unwrap();
+        Pin::new(thread).poll(cx)
+    }
+}
+
+impl<'a, Th, F> Drop for OutOfLine<'a, Th, F> {
+    fn drop(&mut self) {
+        // the stack may still borrow the closure
+        self.thread = None;
+        unsafe { core::ptr::drop_in_place(self.slot.as_mut_ptr()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yield_now;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn capture_larger_than_thread() {
+        let buf = [1u8; 0x3000];
+        let mut slot = Box::new(MaybeUninit::uninit());
+        let ret = out_of_line(
+            move || {
+                yield_now();
+                buf.iter().map(|&x| x as usize).sum::<usize>()
+            },
+            &mut *slot,
+        )
+        .await;
+        assert_eq!(ret, 0x3000);
+    }
+
+    #[tokio::test]
+    async fn drop_closure_in_slot() {
+        let shared = Arc::new(());
+        let captured = shared.clone();
+        let mut slot = MaybeUninit::uninit();
+        out_of_line(
+            move || {
+                let _ = &captured;
+            },
+            &mut slot,
+        )
+        .await;
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+}
@@ -0,0 +1,115 @@
+//! Threads placed in an aligned region provided by the caller.
+
+use crate::ThreadFuture;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::task::{Context, Poll};
+
+/// A thread built in place in a `'static` region provided by the caller.
+///
+/// It is the same [`ThreadFuture`], with the TCB at the start of the region and
+/// the stack above it, so the region must be exactly 0x2000 bytes aligned to
+/// 0x2000. The handle is only a pointer to it, so unlike a `ThreadFuture` held
+/// by value, it can be moved freely even after being polled.
+pub struct PlacedThread<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    thread: &'static mut ThreadFuture<F, T>,
+}
+
+/// Size and alignment of the region of a thread.
+const SIZE: usize = core::mem::size_of::<ThreadFuture<fn(), ()>>();
+
+impl<F, T> PlacedThread<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    /// Create a thread running `f` in `region`.
+    ///
+    /// Panics if `region` is not exactly 0x2000 bytes, or not aligned to 0x2000.
+    pub fn new(region: &'static mut [u8], f: F) -> Self {
+        assert_eq!(region.len(), SIZE, "the region is not 0x2000 bytes");
+        assert_eq!(
+            region.as_ptr() as usize % SIZE,
+            0,
+            "the region is not aligned to 0x2000"
+        );
+        // the region is borrowed forever, so nothing else can access it
+        let thread = unsafe { ThreadFuture::from_raw(NonNull::from(region).cast(), f) };
+        PlacedThread { thread }
+    }
+}
+
+impl<F, T> Future for PlacedThread<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().thread.poll_unpin(cx)
+    }
+}
+
+impl<F, T> Drop for PlacedThread<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    fn drop(&mut self) {
+        unsafe { core::ptr::drop_in_place(self.thread as *mut ThreadFuture<F, T>) }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tests::noop_waker;
+    use crate::yield_now;
+
+    /// A leaked region of the size and alignment of a thread.
+    ///
+    /// It is allocated directly, since it does not fit on the stack of a thread.
+    pub(crate) fn region() -> &'static mut [u8] {
+        let layout = std::alloc::Layout::from_size_align(SIZE, SIZE).unwrap();
+        unsafe {
+            let ptr = std::alloc::alloc_zeroed(layout);
+            assert!(!ptr.is_null(), "out of memory");
+            core::slice::from_raw_parts_mut(ptr, SIZE)
+        }
+    }
+
+    #[tokio::test]
+    async fn move_after_first_poll() {
+        let mut thread = PlacedThread::new(region(), || {
+            yield_now();
+            1u32
+        });
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut thread).poll(&mut cx).is_pending());
+        let moved = thread;
+        assert_eq!(moved.await, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "the region is not 0x2000 bytes")]
+    fn oversized_region() {
+        let region = Box::leak(vec![0u8; SIZE * 2].into_boxed_slice());
+        PlacedThread::new(region, || ());
+    }
+
+    #[test]
+    #[should_panic(expected = "the region is not aligned to 0x2000")]
+    fn unaligned_region() {
+        let buf = Box::leak(vec![0u8; SIZE * 2].into_boxed_slice());
+        // the right size, at an unaligned start
+        let start = if buf.as_ptr() as usize % SIZE == 0 { 8 } else { 0 };
+        PlacedThread::new(&mut buf[start..start + SIZE], || ());
+    }
+}
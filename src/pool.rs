@@ -0,0 +1,691 @@
+//! A work-stealing executor running green threads on a pool of OS threads.
+
+use crate::{Enqueue, RegSet, ThreadFuture};
+use core::any::Any;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+/// An M:N executor running green threads on a pool of OS threads.
+///
+/// Each worker has a local ready queue. A woken thread is pushed back to
+/// the queue of the worker which ran it last, and an idle worker steals
+/// from the back of the others.
+///
+/// A thread which yields is put at the front or back of the queue
+/// by its [`Enqueue`] hint.
+///
+/// A thread with an [`affinity`](ThreadFuture::affinity) is only put in the
+/// queue of the worker of that number, and is never stolen.
+///
+/// A thread which panics is dropped, and the payload is kept for
+/// [`take_panics`](Self::take_panics). The worker goes on with other threads.
+pub struct ThreadPoolExecutor {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    /// Ready queues of workers.
+    queues: Vec<Mutex<VecDeque<Arc<Task>>>>,
+    /// Number of spawned tasks which have not finished.
+    pending: AtomicUsize,
+    /// Round-robin counter to place new tasks. It also numbers them.
+    next: AtomicUsize,
+    /// Number of stack colors to assign round-robin, or 0 to not color.
+    colors: AtomicUsize,
+    /// All spawned tasks, for [`ThreadPoolExecutor::dump`].
+    tasks: Mutex<Vec<Weak<Task>>>,
+    /// Whether the workers should exit.
+    shutdown: AtomicBool,
+    /// Bumped whenever a task is pushed, to wake idle workers.
+    signal: Mutex<usize>,
+    /// Idle workers wait here for `signal` to change.
+    wakeup: Condvar,
+    /// Payloads of threads which panicked.
+    panics: Mutex<Vec<Box<dyn Any + Send>>>,
+}
+
+/// A spawned thread with its type erased.
+pub(crate) trait Runnable: Send {
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+
+    /// Where to enqueue the thread after it switched out.
+    fn enqueue_hint(&self) -> Enqueue;
+
+    /// The name of the thread.
+    fn name(&self) -> Option<&'static str>;
+
+    /// Where the thread will resume, if it has been polled.
+    fn saved_pc(&self) -> Option<usize>;
+
+    /// The stack high-water, if the stack is poisoned.
+    fn stack_used_bytes(&self) -> Option<usize>;
+
+    /// The core the thread is pinned to, if any.
+    fn affinity(&self) -> Option<usize>;
+}
+
+impl<F, T> Runnable for ThreadFuture<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_unpin(cx).map(drop)
+    }
+
+    fn enqueue_hint(&self) -> Enqueue {
+        // a parked thread gave no hint
+        if self.is_yielded() {
+            ThreadFuture::enqueue_hint(self)
+        } else {
+            Enqueue::Back
+        }
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        ThreadFuture::name(self)
+    }
+
+    fn saved_pc(&self) -> Option<usize> {
+        // the caller holds the lock of the task, so it is not running
+        if unsafe { self.tcb.context_ptr.is_null() } {
+            None
+        } else {
+            Some(unsafe { ThreadFuture::saved_pc(self) })
+        }
+    }
+
+    fn stack_used_bytes(&self) -> Option<usize> {
+        ThreadFuture::stack_used_bytes(self)
+    }
+
+    fn affinity(&self) -> Option<usize> {
+        ThreadFuture::affinity(self)
+    }
+}
+
+/// The task is waiting to be woken.
+const IDLE: usize = 0;
+/// The task is in a ready queue.
+const SCHEDULED: usize = 1;
+/// The task is being polled.
+const RUNNING: usize = 2;
+/// The task is woken while being polled.
+const NOTIFIED: usize = 3;
+/// The thread has exited.
+const DONE: usize = 4;
+
+struct Task {
+    /// Number given at spawn.
+    id: usize,
+    /// The thread. Boxed since it must not move after the first poll.
+    thread: Mutex<Option<Box<dyn Runnable>>>,
+    /// One of `IDLE`, `SCHEDULED`, `RUNNING`, `NOTIFIED` and `DONE`.
+    state: AtomicUsize,
+    /// Index of the worker which ran it last.
+    home: AtomicUsize,
+    /// Index of the only worker which may run it.
+    affinity: Option<usize>,
+    shared: Arc<Shared>,
+}
+
+impl ThreadPoolExecutor {
+    /// Create an executor with `workers` OS threads.
+    pub fn new(workers: usize) -> Self {
+        assert!(workers > 0, "no worker");
+        let shared = Arc::new(Shared {
+            queues: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            pending: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+            colors: AtomicUsize::new(0),
+            tasks: Mutex::new(Vec::new()),
+            shutdown: AtomicBool::new(false),
+            signal: Mutex::new(0),
+            wakeup: Condvar::new(),
+            panics: Mutex::new(Vec::new()),
+        });
+        let workers = (0..workers)
+            .map(|id| {
+                let shared = shared.clone();
+                std::thread::spawn(move || shared.run_worker(id))
+            })
+            .collect();
+        ThreadPoolExecutor { shared, workers }
+    }
+
+    /// Give spawned threads `colors` different stack colors in turn.
+    ///
+    /// See [`ThreadFuture::with_stack_color`] for the tradeoff.
+    /// Threads which have a color already keep it, even a color of 0.
+    ///
+    /// Panics if `colors` is more than 33, since a color takes at most
+    /// a quarter of the stack.
+    pub fn with_stack_coloring(self, colors: usize) -> Self {
+        assert!(
+            colors <= crate::MAX_STACK_COLOR / crate::CACHE_LINE + 1,
+            "too many stack colors"
+        );
+        self.shared.colors.store(colors, Ordering::Relaxed);
+        self
+    }
+
+    /// Spawn a thread. Its return value is dropped.
+    ///
+    /// Panics if the thread is pinned to a worker which does not exist,
+    /// or preserves [`RegSet::TLS`], which would carry the thread pointer
+    /// of one worker to another.
+    pub fn spawn<F, T>(&self, mut thread: ThreadFuture<F, T>)
+    where
+        F: Send + 'static + Unpin + FnOnce() -> T,
+        T: Send + 'static + Unpin,
+    {
+        assert!(
+            unsafe { !thread.tcb.regs.contains(RegSet::TLS) },
+            "a thread preserving TLS cannot move between workers"
+        );
+        let workers = self.shared.queues.len();
+        let affinity = thread.affinity();
+        if let Some(core) = affinity {
+            assert!(core < workers, "affinity to worker {} of {}", core, workers);
+        }
+        let id = self.shared.next.fetch_add(1, Ordering::Relaxed);
+        let home = affinity.unwrap_or(id % workers);
+        let colors = self.shared.colors.load(Ordering::Relaxed);
+        if colors != 0 && unsafe { thread.tcb.color.is_none() } {
+            thread.set_stack_color(id % colors);
+        }
+        let task = Arc::new(Task {
+            id,
+            thread: Mutex::new(Some(Box::new(thread))),
+            state: AtomicUsize::new(IDLE),
+            home: AtomicUsize::new(home),
+            affinity,
+            shared: self.shared.clone(),
+        });
+        self.shared.pending.fetch_add(1, Ordering::AcqRel);
+        let mut tasks = self.shared.tasks.lock().unwrap();
+        tasks.retain(|task| task.strong_count() != 0);
+        tasks.push(Arc::downgrade(&task));
+        drop(tasks);
+        task.wake();
+    }
+
+    /// Write a table of all live threads to `out`, one line per thread.
+    ///
+    /// Each line has the spawn number, the state, the PC where the thread
+    /// will resume, the stack high-water and its name. A thread being polled
+    /// is `running` and its PC is unknown. A thread not polled yet has no PC
+    /// either. The high-water is only known for threads built with
+    /// [`with_stack_poison`](ThreadFuture::with_stack_poison).
+    ///
+    /// It is meant for debugging a wedged executor, and takes the lock of
+    /// each thread in turn, so it only sees a rough snapshot.
+    pub fn dump(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "{:<6} {:<9} {:<18} {:<6} NAME",
+            "ID", "STATE", "PC", "USED"
+        )?;
+        let tasks: Vec<_> = self
+            .shared
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for task in tasks {
+            let (state, pc, used, name) = match task.thread.try_lock() {
+                Ok(thread) => match thread.as_ref() {
+                    Some(thread) => {
+                        let state = match task.state.load(Ordering::Acquire) {
+                            SCHEDULED => "ready",
+                            // just polled, but the state is not updated yet
+                            RUNNING | NOTIFIED => "running",
+                            _ => "parked",
+                        };
+                        (
+                            state,
+                            thread.saved_pc(),
+                            thread.stack_used_bytes(),
+                            thread.name(),
+                        )
+                    }
+                    None => continue,
+                },
+                Err(_) => ("running", None, None, None),
+            };
+            let pc = match pc {
+                Some(pc) => format!("{:#018x}", pc),
+                None => "-".into(),
+            };
+            let used = match used {
+                Some(used) => used.to_string(),
+                None => "-".into(),
+            };
+            writeln!(
+                out,
+                "{:<6} {:<9} {:<18} {:<6} {}",
+                task.id,
+                state,
+                pc,
+                used,
+                name.unwrap_or("-")
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Spawn a thread running `f` within the stack budget.
+    ///
+    /// Returns `f` back if the budget set by
+    /// [`set_stack_budget`](crate::set_stack_budget) would be exceeded.
+    pub fn try_spawn<F, T>(&self, f: F) -> Result<(), F>
+    where
+        F: Send + 'static + Unpin + FnOnce() -> T,
+        T: Send + 'static + Unpin,
+    {
+        self.spawn(ThreadFuture::try_new(f)?);
+        Ok(())
+    }
+
+    /// Block the current OS thread until all spawned threads have exited
+    /// or panicked.
+    pub fn wait_idle(&self) {
+        while self.shared.pending.load(Ordering::Acquire) != 0 {
+            std::thread::yield_now();
+        }
+    }
+
+    /// Take the panic payloads of threads which panicked so far.
+    pub fn take_panics(&self) -> Vec<Box<dyn Any + Send>> {
+        core::mem::take(&mut *self.shared.panics.lock().unwrap())
+    }
+}
+
+impl Drop for ThreadPoolExecutor {
+    /// Stop the workers. Threads which have not exited are dropped, parked
+    /// ones included, but like any dropped [`ThreadFuture`] their frames are
+    /// freed without running destructors.
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        self.shared.notify();
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+        // a parked thread holds its own task through its waker, so it is not
+        // freed with the queues, and each thread is dropped by hand
+        let tasks: Vec<_> = self
+            .shared
+            .tasks
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter_map(|task| task.upgrade())
+            .collect();
+        for task in tasks {
+            let thread = task.thread.lock().unwrap().take();
+            drop(thread);
+        }
+        for queue in self.shared.queues.iter() {
+            queue.lock().unwrap().clear();
+        }
+    }
+}
+
+impl Shared {
+    fn run_worker(&self, id: usize) {
+        while !self.shutdown.load(Ordering::Acquire) {
+            // read before looking at the queues, so a push after it is not missed
+            let seen = *self.signal.lock().unwrap();
+            match self.pop_or_steal(id) {
+                Some(task) => {
+                    task.home.store(id, Ordering::Relaxed);
+                    task.run();
+                }
+                None => {
+                    let mut signal = self.signal.lock().unwrap();
+                    while *signal == seen && !self.shutdown.load(Ordering::Acquire) {
+                        signal = self.wakeup.wait(signal).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wake all idle workers.
+    ///
+    /// A pinned task can only be run by its worker, so all of them are woken.
+    fn notify(&self) {
+        *self.signal.lock().unwrap() += 1;
+        self.wakeup.notify_all();
+    }
+
+    /// Pop from the front of the own queue, or steal from the back of others.
+    ///
+    /// Pinned tasks are never stolen.
+    fn pop_or_steal(&self, id: usize) -> Option<Arc<Task>> {
+        if let Some(task) = self.queues[id].lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        let n = self.queues.len();
+        (1..n).find_map(|i| {
+            let mut queue = self.queues[(id + i) % n].lock().unwrap();
+            let index = queue.iter().rposition(|task| task.affinity.is_none())?;
+            queue.remove(index)
+        })
+    }
+}
+
+impl Task {
+    /// Schedule the task unless it is scheduled or running.
+    ///
+    /// A task woken while running is enqueued by the worker after the poll,
+    /// when the hint of the thread is known.
+    fn wake(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            let new = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return,
+            };
+            match self
+                .state
+                .compare_exchange_weak(state, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(s) => state = s,
+            }
+        }
+        if state == IDLE {
+            self.push(Enqueue::Back);
+        }
+    }
+
+    /// Push to the ready queue of its home worker.
+    fn push(self: &Arc<Self>, enqueue: Enqueue) {
+        let home = self.home.load(Ordering::Relaxed);
+        let mut queue = self.shared.queues[home].lock().unwrap();
+        match enqueue {
+            Enqueue::Back => queue.push_back(self.clone()),
+            Enqueue::Front => queue.push_front(self.clone()),
+        }
+        drop(queue);
+        self.shared.notify();
+    }
+
+    /// Poll the thread once.
+    fn run(self: &Arc<Self>) {
+        self.state.store(RUNNING, Ordering::Release);
+        let mut thread = self.thread.lock().unwrap();
+        let runnable = thread.as_mut().unwrap();
+        let waker = unsafe { Waker::from_raw(raw_waker(self.clone())) };
+        let mut cx = Context::from_waker(&waker);
+        // the panic is caught here, so it neither kills the worker nor
+        // poisons the lock, and the unwound thread can be dropped
+        let ready = match std::panic::catch_unwind(AssertUnwindSafe(|| runnable.poll(&mut cx))) {
+            Ok(poll) => poll.is_ready(),
+            Err(payload) => {
+                self.shared.panics.lock().unwrap().push(payload);
+                true
+            }
+        };
+        if ready {
+            *thread = None;
+            self.state.store(DONE, Ordering::Release);
+            self.shared.pending.fetch_sub(1, Ordering::AcqRel);
+            return;
+        }
+        let enqueue = runnable.enqueue_hint();
+        drop(thread);
+        let idle = self
+            .state
+            .compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire);
+        if idle.is_err() {
+            // woken while running
+            self.state.store(SCHEDULED, Ordering::Release);
+            self.push(enqueue);
+        }
+    }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+
+fn raw_waker(task: Arc<Task>) -> RawWaker {
+    RawWaker::new(Arc::into_raw(task) as *const (), &VTABLE)
+}
+
+unsafe fn clone(data: *const ()) -> RawWaker {
+    let task = Arc::from_raw(data as *const Task);
+    let cloned = task.clone();
+    core::mem::forget(task);
+    raw_waker(cloned)
+}
+
+unsafe fn wake(data: *const ()) {
+    let task = Arc::from_raw(data as *const Task);
+    task.wake();
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    let task = Arc::from_raw(data as *const Task);
+    task.wake();
+    core::mem::forget(task);
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(Arc::from_raw(data as *const Task));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::SpinLock;
+    use crate::yield_now;
+    #[cfg(feature = "hints")]
+    use crate::{yield_now_back, yield_now_front};
+    #[cfg(feature = "hints")]
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn dump() {
+        static PARKED: AtomicUsize = AtomicUsize::new(0);
+        let pool = ThreadPoolExecutor::new(2);
+        for name in ["alpha", "beta"].iter() {
+            pool.spawn(
+                ThreadFuture::from(|| loop {
+                    PARKED.fetch_add(1, Ordering::SeqCst);
+                    crate::park();
+                })
+                .with_name(*name)
+                .with_stack_poison(),
+            );
+        }
+        let mut out = String::new();
+        // wait until both have switched out at `park`
+        for _ in 0..1000 {
+            out.clear();
+            pool.dump(&mut out).unwrap();
+            if PARKED.load(Ordering::SeqCst) == 2 && out.matches("parked").count() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", out);
+        assert!(lines[0].starts_with("ID"));
+        for (line, name) in lines[1..].iter().zip(["alpha", "beta"].iter()) {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            assert_eq!(fields[1], "parked");
+            assert!(fields[2].starts_with("0x"));
+            assert!(fields[3].parse::<usize>().unwrap() > 0);
+            assert_eq!(fields[4], *name);
+        }
+    }
+
+    #[test]
+    fn drop_frees_parked_threads() {
+        static PARKED: AtomicUsize = AtomicUsize::new(0);
+        let pool = ThreadPoolExecutor::new(2);
+        for _ in 0..4 {
+            pool.spawn(ThreadFuture::from(|| {
+                PARKED.fetch_add(1, Ordering::SeqCst);
+                crate::park();
+            }));
+        }
+        while PARKED.load(Ordering::SeqCst) != 4 {
+            std::thread::yield_now();
+        }
+        let tasks = pool.shared.tasks.lock().unwrap().clone();
+        drop(pool);
+        // the waker in the TCB kept the task, and so the thread, alive
+        assert!(tasks.iter().all(|task| task.upgrade().is_none()));
+    }
+
+    #[test]
+    fn panicking_thread() {
+        let pool = ThreadPoolExecutor::new(1);
+        pool.spawn(ThreadFuture::from(|| {
+            yield_now();
+            panic!("boom");
+        }));
+        pool.spawn(ThreadFuture::from(yield_now));
+        pool.wait_idle();
+        let panics = pool.take_panics();
+        assert_eq!(panics.len(), 1);
+        assert_eq!(panics[0].downcast_ref::<&str>(), Some(&"boom"));
+        // the worker survived the panic
+        pool.spawn(ThreadFuture::from(yield_now));
+        pool.wait_idle();
+    }
+
+    #[test]
+    fn many_yielding_threads() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let pool = ThreadPoolExecutor::new(4);
+        for _ in 0..100 {
+            pool.spawn(ThreadFuture::from(|| {
+                for _ in 0..10 {
+                    COUNTER.fetch_add(1, Ordering::SeqCst);
+                    yield_now();
+                }
+            }));
+        }
+        pool.wait_idle();
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1000);
+    }
+
+    #[test]
+    fn stack_coloring() {
+        // (explicit color, offset of the stack pointer in the thread)
+        static OFFSETS: SpinLock<Vec<(bool, usize)>> = SpinLock::new(Vec::new());
+        let record = |explicit| {
+            move || {
+                let sp = unsafe { crate::stack_pointer() };
+                OFFSETS.lock().push((explicit, sp % crate::RAW_SIZE));
+                yield_now();
+            }
+        };
+        let pool = ThreadPoolExecutor::new(2).with_stack_coloring(4);
+        for _ in 0..5 {
+            pool.spawn(ThreadFuture::from(record(false)));
+        }
+        // the pool would give it a color of 1
+        pool.spawn(ThreadFuture::from(record(true)).with_stack_color(0));
+        pool.wait_idle();
+        let offsets = OFFSETS.lock();
+        assert_eq!(offsets.len(), 6);
+        let mut colored: Vec<_> = offsets.iter().filter(|o| !o.0).map(|o| o.1).collect();
+        colored.sort();
+        colored.dedup();
+        assert_eq!(colored.len(), 4);
+        for pair in colored.windows(2) {
+            assert_eq!(pair[1] - pair[0], crate::CACHE_LINE);
+        }
+        let explicit = offsets.iter().find(|o| o.0).unwrap().1;
+        assert_eq!(explicit, *colored.last().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "too many stack colors")]
+    fn too_many_stack_colors() {
+        let _pool = ThreadPoolExecutor::new(1).with_stack_coloring(34);
+    }
+
+    #[cfg(feature = "hints")]
+    #[test]
+    fn affine_thread_stays_on_its_worker() {
+        static SEEN: SpinLock<Vec<(usize, std::thread::ThreadId)>> = SpinLock::new(Vec::new());
+        let pool = ThreadPoolExecutor::new(4);
+        // idle workers try to steal them all the time
+        for id in 0..8 {
+            pool.spawn(
+                ThreadFuture::from(move || {
+                    for _ in 0..50 {
+                        SEEN.lock().push((id, std::thread::current().id()));
+                        yield_now();
+                    }
+                })
+                .with_affinity(id % 2),
+            );
+        }
+        pool.wait_idle();
+        let seen = SEEN.lock();
+        assert_eq!(seen.len(), 8 * 50);
+        // the OS thread of each worker, by the first run of a thread pinned to it
+        let workers: Vec<_> = (0..2)
+            .map(|worker| seen.iter().find(|(id, _)| id % 2 == worker).unwrap().1)
+            .collect();
+        assert!(seen.iter().all(|(id, thread)| *thread == workers[id % 2]));
+        assert_ne!(workers[0], workers[1]);
+    }
+
+    /// Run two threads on one worker, each logging 3 times with `yield_fn`.
+    #[cfg(feature = "hints")]
+    fn enqueue_order(yield_fn: fn()) -> Vec<u8> {
+        static READY: AtomicBool = AtomicBool::new(false);
+        static LOG: SpinLock<Vec<u8>> = SpinLock::new(Vec::new());
+        READY.store(false, Ordering::SeqCst);
+        LOG.lock().clear();
+        let pool = ThreadPoolExecutor::new(1);
+        for id in 0..2 {
+            pool.spawn(ThreadFuture::from(move || {
+                // wait until both are spawned
+                while !READY.load(Ordering::SeqCst) {
+                    yield_now();
+                }
+                for _ in 0..3 {
+                    LOG.lock().push(id);
+                    yield_fn();
+                }
+            }));
+        }
+        READY.store(true, Ordering::SeqCst);
+        pool.wait_idle();
+        let log = LOG.lock().clone();
+        log
+    }
+
+    #[cfg(feature = "hints")]
+    #[test]
+    fn enqueue_hint() {
+        let log = enqueue_order(yield_now_front);
+        assert_eq!(log[0], log[1]);
+        assert_eq!(log[1], log[2]);
+        assert_ne!(log[2], log[3]);
+        assert_eq!(log[3], log[4]);
+        assert_eq!(log[4], log[5]);
+
+        let log = enqueue_order(yield_now_back);
+        assert!(log.windows(2).all(|w| w[0] != w[1]));
+    }
+}
@@ -1,3 +1,9 @@
+/// Bytes reserved at the top of a new stack, above the initial context.
+///
+/// The stack top is already 16-byte aligned as the ABI requires,
+/// and the size of context is rounded up to keep it aligned.
+const STACK_TOP_RESERVED: usize = 0;
+
 /// Saved registers of a thread.
 #[repr(C)]
 #[derive(Debug)]
@@ -6,12 +12,15 @@ struct ThreadContext {
     s: [usize; 12],
     /// Return address
     ra: usize,
+    /// Round the size up to a multiple of 16 bytes
+    _pad: [usize; 16 / XLEN_BYTES - 1],
 }
 
 #[cfg(target_arch = "riscv32")]
 global_asm!(
     r"
 .equ XLENB, 4
+.equ CTXSIZE, 64
 .macro LOAD reg, mem
     lw \reg, \mem
 .endm
@@ -23,6 +32,7 @@ global_asm!(
 global_asm!(
     r"
 .equ XLENB, 8
+.equ CTXSIZE, 112
 .macro LOAD reg, mem
     ld \reg, \mem
 .endm
@@ -31,13 +41,42 @@ global_asm!(
 .endm"
 );
 
+/// Bytes of a register.
+const XLEN_BYTES: usize = core::mem::size_of::<usize>();
+
+/// Offsets of the saved registers in `ThreadContext`.
+const CONTEXT_REGISTERS: &[(&str, usize)] = &[
+    ("s0", 0),
+    ("s1", XLEN_BYTES),
+    ("s2", 2 * XLEN_BYTES),
+    ("s3", 3 * XLEN_BYTES),
+    ("s4", 4 * XLEN_BYTES),
+    ("s5", 5 * XLEN_BYTES),
+    ("s6", 6 * XLEN_BYTES),
+    ("s7", 7 * XLEN_BYTES),
+    ("s8", 8 * XLEN_BYTES),
+    ("s9", 9 * XLEN_BYTES),
+    ("s10", 10 * XLEN_BYTES),
+    ("s11", 11 * XLEN_BYTES),
+    ("ra", 12 * XLEN_BYTES),
+];
+
+/// Offset of the program counter in `ThreadContext`.
+const CONTEXT_PC_OFFSET: usize = 12 * XLEN_BYTES;
+
+/// Offset of the frame pointer in `ThreadContext`.
+const CONTEXT_FP_OFFSET: usize = 0;
+
+/// Offset of the entry of a new thread in its initial context.
+const CONTEXT_ENTRY_OFFSET: usize = 2 * XLEN_BYTES;
+
 impl ThreadContext {
     /// Switch context to another thread.
     #[naked]
     #[inline(never)]
     unsafe extern "C" fn switch(_ptr_ptr: *mut *mut Self) {
         asm!(r#"
-        addi  sp, sp, (-XLENB*13)
+        addi  sp, sp, -CTXSIZE
         STORE s0, 0*XLENB(sp)
         STORE s1, 1*XLENB(sp)
         STORE s2, 2*XLENB(sp)
@@ -69,7 +108,7 @@ impl ThreadContext {
         LOAD s10, 10*XLENB(sp)
         LOAD s11, 11*XLENB(sp)
         LOAD ra, 12*XLENB(sp)
-        addi sp, sp, (XLENB*13)
+        addi sp, sp, CTXSIZE
         "# :::: "volatile");
     }
 
@@ -77,6 +116,316 @@ impl ThreadContext {
     fn set_pc(&mut self, pc: usize) {
         self.ra = pc;
     }
+
+    /// Get value of program counter.
+    fn pc(&self) -> usize {
+        self.ra
+    }
+
+    /// Set value of frame pointer.
+    fn set_fp(&mut self, fp: usize) {
+        self.s[0] = fp;
+    }
+
+    /// Make the initial context enter `pc` with `arg` in the first argument register.
+    ///
+    /// `switch` only restores callee-saved registers, so they are parked in
+    /// `s2` and `s1`, and the first switch returns to `trampoline` to move
+    /// them into place.
+    fn set_entry(&mut self, pc: usize, arg: usize) {
+        self.ra = Self::trampoline as usize;
+        self.s[2] = pc;
+        self.s[1] = arg;
+    }
+
+    /// Jump to the entry of a new thread set by `set_entry`.
+    ///
+    /// The return address is cleared to end the frame chain at the entry.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "C" fn trampoline() {
+        asm!(r#"
+        mv a0, s1
+        mv ra, zero
+        jr s2
+        "# :::: "volatile");
+    }
+}
+
+/// Optional registers of a thread, swapped in `switch_ext` by its `RegSet`.
+///
+/// `FP` is fs0-fs11 with `fcsr`, if the D extension is enabled. `TLS` is `tp`.
+/// `VECTOR` is empty, since the vector extension has no callee-saved state.
+///
+/// `switch_ext` accesses the fields by offset: `tp` at 0, `fcsr` at `XLENB`
+/// and `fs` at `2*XLENB`.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ExtContext {
+    tp: usize,
+    #[cfg(target_feature = "d")]
+    fcsr: usize,
+    #[cfg(target_feature = "d")]
+    fs: [u64; 12],
+}
+
+/// Swap fs0-fs11 and `fcsr` in `switch_ext`: save them to the executor copy
+/// at `8*XLENB(sp)`, load them from the thread copy at `(s0)`.
+#[cfg(target_feature = "d")]
+macro_rules! swap_fp_in {
+    () => {
+        r#"
+        andi t0, s1, 2
+        beqz t0, 4f
+        addi t1, sp, 10*XLENB
+        fsd fs0, 0(t1)
+        fsd fs1, 8(t1)
+        fsd fs2, 16(t1)
+        fsd fs3, 24(t1)
+        fsd fs4, 32(t1)
+        fsd fs5, 40(t1)
+        fsd fs6, 48(t1)
+        fsd fs7, 56(t1)
+        fsd fs8, 64(t1)
+        fsd fs9, 72(t1)
+        fsd fs10, 80(t1)
+        fsd fs11, 88(t1)
+        frcsr t2
+        STORE t2, 9*XLENB(sp)
+        addi t1, s0, 2*XLENB
+        fld fs0, 0(t1)
+        fld fs1, 8(t1)
+        fld fs2, 16(t1)
+        fld fs3, 24(t1)
+        fld fs4, 32(t1)
+        fld fs5, 40(t1)
+        fld fs6, 48(t1)
+        fld fs7, 56(t1)
+        fld fs8, 64(t1)
+        fld fs9, 72(t1)
+        fld fs10, 80(t1)
+        fld fs11, 88(t1)
+        LOAD t2, 1*XLENB(s0)
+        fscsr t2
+    4:
+        "#
+    };
+}
+#[cfg(not(target_feature = "d"))]
+macro_rules! swap_fp_in {
+    () => {
+        ""
+    };
+}
+
+/// Swap fs0-fs11 and `fcsr` back in `switch_ext`: save them to the thread
+/// copy at `(s0)`, load them from the executor copy at `8*XLENB(sp)`.
+#[cfg(target_feature = "d")]
+macro_rules! swap_fp_out {
+    () => {
+        r#"
+        andi t0, s1, 2
+        beqz t0, 5f
+        addi t1, s0, 2*XLENB
+        fsd fs0, 0(t1)
+        fsd fs1, 8(t1)
+        fsd fs2, 16(t1)
+        fsd fs3, 24(t1)
+        fsd fs4, 32(t1)
+        fsd fs5, 40(t1)
+        fsd fs6, 48(t1)
+        fsd fs7, 56(t1)
+        fsd fs8, 64(t1)
+        fsd fs9, 72(t1)
+        fsd fs10, 80(t1)
+        fsd fs11, 88(t1)
+        frcsr t2
+        STORE t2, 1*XLENB(s0)
+        addi t1, sp, 10*XLENB
+        fld fs0, 0(t1)
+        fld fs1, 8(t1)
+        fld fs2, 16(t1)
+        fld fs3, 24(t1)
+        fld fs4, 32(t1)
+        fld fs5, 40(t1)
+        fld fs6, 48(t1)
+        fld fs7, 56(t1)
+        fld fs8, 64(t1)
+        fld fs9, 72(t1)
+        fld fs10, 80(t1)
+        fld fs11, 88(t1)
+        LOAD t2, 9*XLENB(sp)
+        fscsr t2
+    5:
+        "#
+    };
+}
+#[cfg(not(target_feature = "d"))]
+macro_rules! swap_fp_out {
+    () => {
+        ""
+    };
+}
+
+impl ThreadContext {
+    /// Call the `switch` at `_switch` with the register groups in the bits of
+    /// `_regs` swapped: the executor ones are kept on this stack, and the
+    /// thread ones are loaded from `_ext` before and saved to it after.
+    ///
+    /// It is one asm block, so the compiler cannot allocate fs0-fs11 between
+    /// loading the values of the thread and switching to it. The frame holds
+    /// ra and s0-s3, then the executor copy of `ExtContext` at `8*XLENB`.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "C" fn switch_ext(
+        _ptr_ptr: *mut *mut Self,
+        _ext: *mut ExtContext,
+        _regs: usize,
+        _switch: usize,
+    ) {
+        asm!(concat!(r#"
+        addi  sp, sp, -40*XLENB
+        STORE ra, 0*XLENB(sp)
+        STORE s0, 1*XLENB(sp)
+        STORE s1, 2*XLENB(sp)
+        STORE s2, 3*XLENB(sp)
+        STORE s3, 4*XLENB(sp)
+
+        mv s0, a1
+        mv s1, a2
+        mv s2, a0
+        mv s3, a3
+
+        andi t0, s1, 4
+        beqz t0, 2f
+        STORE tp, 8*XLENB(sp)
+        LOAD tp, 0(s0)
+    2:
+        "#, swap_fp_in!(), r#"
+        mv a0, s2
+        jalr s3
+
+        andi t0, s1, 4
+        beqz t0, 3f
+        STORE tp, 0(s0)
+        LOAD tp, 8*XLENB(sp)
+    3:
+        "#, swap_fp_out!(), r#"
+        LOAD ra, 0*XLENB(sp)
+        LOAD s0, 1*XLENB(sp)
+        LOAD s1, 2*XLENB(sp)
+        LOAD s2, 3*XLENB(sp)
+        LOAD s3, 4*XLENB(sp)
+        addi sp, sp, 40*XLENB
+        "#) :::: "volatile");
+    }
+}
+
+impl ExtContext {
+    /// Save the register groups in `regs` from the CPU, which a new thread
+    /// starts with.
+    #[inline(always)]
+    unsafe fn save(&mut self, regs: RegSet) {
+        #[cfg(target_feature = "d")]
+        if regs.contains(RegSet::FP) {
+            asm!(r#"
+            fsd fs0, 0($0)
+            fsd fs1, 8($0)
+            fsd fs2, 16($0)
+            fsd fs3, 24($0)
+            fsd fs4, 32($0)
+            fsd fs5, 40($0)
+            fsd fs6, 48($0)
+            fsd fs7, 56($0)
+            fsd fs8, 64($0)
+            fsd fs9, 72($0)
+            fsd fs10, 80($0)
+            fsd fs11, 88($0)
+            "# :: "r"(self.fs.as_mut_ptr()) : "memory" : "volatile");
+            asm!("frcsr $0" : "=r"(self.fcsr) ::: "volatile");
+        }
+        if regs.contains(RegSet::TLS) {
+            asm!("mv $0, tp" : "=r"(self.tp) ::: "volatile");
+        }
+    }
+}
+
+/// Number of registers set by `switch_marked`.
+#[cfg(test)]
+const CALLEE_SAVED: usize = 12;
+
+/// Set callee-saved registers to `base + i` in the order of `ThreadContext`,
+/// call `switch` with `ptr_ptr`, then store the registers found after
+/// switching back to `out`. It is used by the conformance tests.
+#[cfg(test)]
+#[naked]
+#[inline(never)]
+unsafe extern "C" fn switch_marked(
+    _ptr_ptr: *mut *mut ThreadContext,
+    _out: *mut usize,
+    _base: usize,
+    _switch: usize,
+) {
+    asm!(r#"
+    addi  sp, sp, -16*XLENB
+    STORE s0, 0*XLENB(sp)
+    STORE s1, 1*XLENB(sp)
+    STORE s2, 2*XLENB(sp)
+    STORE s3, 3*XLENB(sp)
+    STORE s4, 4*XLENB(sp)
+    STORE s5, 5*XLENB(sp)
+    STORE s6, 6*XLENB(sp)
+    STORE s7, 7*XLENB(sp)
+    STORE s8, 8*XLENB(sp)
+    STORE s9, 9*XLENB(sp)
+    STORE s10, 10*XLENB(sp)
+    STORE s11, 11*XLENB(sp)
+    STORE ra, 12*XLENB(sp)
+    STORE a1, 13*XLENB(sp)
+
+    mv   s0, a2
+    addi s1, a2, 1
+    addi s2, a2, 2
+    addi s3, a2, 3
+    addi s4, a2, 4
+    addi s5, a2, 5
+    addi s6, a2, 6
+    addi s7, a2, 7
+    addi s8, a2, 8
+    addi s9, a2, 9
+    addi s10, a2, 10
+    addi s11, a2, 11
+    jalr a3
+
+    LOAD a1, 13*XLENB(sp)
+    STORE s0, 0*XLENB(a1)
+    STORE s1, 1*XLENB(a1)
+    STORE s2, 2*XLENB(a1)
+    STORE s3, 3*XLENB(a1)
+    STORE s4, 4*XLENB(a1)
+    STORE s5, 5*XLENB(a1)
+    STORE s6, 6*XLENB(a1)
+    STORE s7, 7*XLENB(a1)
+    STORE s8, 8*XLENB(a1)
+    STORE s9, 9*XLENB(a1)
+    STORE s10, 10*XLENB(a1)
+    STORE s11, 11*XLENB(a1)
+
+    LOAD s0, 0*XLENB(sp)
+    LOAD s1, 1*XLENB(sp)
+    LOAD s2, 2*XLENB(sp)
+    LOAD s3, 3*XLENB(sp)
+    LOAD s4, 4*XLENB(sp)
+    LOAD s5, 5*XLENB(sp)
+    LOAD s6, 6*XLENB(sp)
+    LOAD s7, 7*XLENB(sp)
+    LOAD s8, 8*XLENB(sp)
+    LOAD s9, 9*XLENB(sp)
+    LOAD s10, 10*XLENB(sp)
+    LOAD s11, 11*XLENB(sp)
+    LOAD ra, 12*XLENB(sp)
+    addi sp, sp, 16*XLENB
+    "# :::: "volatile");
 }
 
 /// Get stack pointer.
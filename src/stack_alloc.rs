@@ -0,0 +1,220 @@
+//! Threads whose memory comes from a custom allocator.
+
+use crate::{ThreadFuture, RAW_SIZE};
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::task::{Context, Poll};
+
+/// An allocator of thread memory, e.g. a heap region or DMA-coherent memory.
+pub trait StackAllocator {
+    /// Allocate `size` bytes aligned to `align`, or return `None` if out of memory.
+    fn alloc(&self, size: usize, align: usize) -> Option<NonNull<u8>>;
+
+    /// Free memory returned by `alloc` with the same `size` and `align`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator and not freed yet.
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, size: usize, align: usize);
+}
+
+impl<A: StackAllocator + ?Sized> StackAllocator for &A {
+    fn alloc(&self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        (**self).alloc(size, align)
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, size: usize, align: usize) {
+        (**self).dealloc(ptr, size, align)
+    }
+}
+
+/// The global allocator.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GlobalStack;
+
+#[cfg(feature = "alloc")]
+impl StackAllocator for GlobalStack {
+    fn alloc(&self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        let layout = core::alloc::Layout::from_size_align(size, align).ok()?;
+        NonNull::new(unsafe { alloc::alloc::alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, size: usize, align: usize) {
+        let layout = core::alloc::Layout::from_size_align_unchecked(size, align);
+        alloc::alloc::dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+/// A thread in memory from a [`StackAllocator`], freed when it is dropped.
+///
+/// The handle is only a pointer, so unlike [`ThreadFuture`] it can be
+/// moved freely even after being polled.
+pub struct BoxedThread<A, F, T>
+where
+    A: StackAllocator,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    thread: NonNull<ThreadFuture<F, T>>,
+    alloc: A,
+}
+
+// the thread is owned like a `Box`
+unsafe impl<A, F, T> Send for BoxedThread<A, F, T>
+where
+    A: StackAllocator + Send,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+}
+
+impl<A, F, T> Unpin for BoxedThread<A, F, T>
+where
+    A: StackAllocator,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+}
+
+impl<F, T> ThreadFuture<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    /// Create a thread running `f` in memory from `alloc`.
+    ///
+    /// Returns the closure back if `alloc` is out of memory.
+    /// Panics if it returns a misaligned region.
+    pub fn boxed_in<A: StackAllocator>(alloc: A, f: F) -> Result<BoxedThread<A, F, T>, F> {
+        let ptr = match alloc.alloc(RAW_SIZE, RAW_SIZE) {
+            Some(ptr) => ptr,
+            None => return Err(f),
+        };
+        // the region is owned by the handle until it is dropped
+        let thread = unsafe { ThreadFuture::from_raw(ptr, f) };
+        Ok(BoxedThread {
+            thread: NonNull::from(thread),
+            alloc,
+        })
+    }
+
+    /// Create a thread running `f` in memory from the global allocator.
+    ///
+    /// Returns the closure back if it is out of memory.
+    #[cfg(feature = "alloc")]
+    pub fn boxed(f: F) -> Result<BoxedThread<GlobalStack, F, T>, F> {
+        Self::boxed_in(GlobalStack, f)
+    }
+}
+
+impl<A, F, T> Deref for BoxedThread<A, F, T>
+where
+    A: StackAllocator,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    type Target = ThreadFuture<F, T>;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.thread.as_ref() }
+    }
+}
+
+impl<A, F, T> DerefMut for BoxedThread<A, F, T>
+where
+    A: StackAllocator,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.thread.as_mut() }
+    }
+}
+
+impl<A, F, T> Future for BoxedThread<A, F, T>
+where
+    A: StackAllocator,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().poll_unpin(cx)
+    }
+}
+
+impl<A, F, T> Drop for BoxedThread<A, F, T>
+where
+    A: StackAllocator,
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    fn drop(&mut self) {
+        unsafe {
+            core::ptr::drop_in_place(self.thread.as_ptr());
+            self.alloc.dealloc(self.thread.cast(), RAW_SIZE, RAW_SIZE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yield_now;
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Hands out the regions of its buffer in turn, and never reuses them.
+    struct Bump {
+        buffer: UnsafeCell<Region>,
+        next: AtomicUsize,
+        freed: AtomicUsize,
+    }
+
+    #[repr(C, align(0x2000))]
+    struct Region([u8; 0x4000]);
+
+    impl StackAllocator for Bump {
+        fn alloc(&self, size: usize, align: usize) -> Option<NonNull<u8>> {
+            let start = self.buffer.get() as usize;
+            let offset = self.next.fetch_add(size, Ordering::SeqCst);
+            let ptr = (start + offset + align - 1) & !(align - 1);
+            if ptr + size > start + 0x4000 {
+                return None;
+            }
+            NonNull::new(ptr as *mut u8)
+        }
+
+        unsafe fn dealloc(&self, _ptr: NonNull<u8>, _size: usize, _align: usize) {
+            self.freed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn stack_from_custom_allocator() {
+        let bump = Bump {
+            buffer: UnsafeCell::new(Region([0; 0x4000])),
+            next: AtomicUsize::new(0),
+            freed: AtomicUsize::new(0),
+        };
+        let region = bump.buffer.get() as usize..bump.buffer.get() as usize + 0x4000;
+        let thread = ThreadFuture::boxed_in(&bump, || {
+            let local = 0u8;
+            yield_now();
+            &local as *const u8 as usize
+        })
+        .ok()
+        .unwrap();
+        assert!(region.contains(&(&*thread as *const _ as usize)));
+        // the stack is in the region too
+        assert!(region.contains(&thread.await));
+        assert_eq!(bump.freed.load(Ordering::SeqCst), 1);
+
+        let _second = ThreadFuture::boxed_in(&bump, yield_now).ok().unwrap();
+        assert!(ThreadFuture::boxed_in(&bump, yield_now).is_err());
+    }
+}
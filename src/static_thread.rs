@@ -0,0 +1,101 @@
+//! Threads placed in `static` memory.
+
+/// Declare a thread whose memory is a `static`, with an accessor to take it.
+///
+/// `static_thread!(NAME, closure)` declares a thread returning `()`, and
+/// `static_thread!(NAME -> T, closure)` one returning `T`.
+/// The closure must not capture anything, since it is stored as `fn() -> T`.
+///
+/// It expands to a function `NAME()` returning `&'static mut ThreadFuture`.
+/// The memory is a static wrapped in `#[repr(align(0x2000))]`, and both its
+/// size and alignment are asserted at compile time. The address is checked
+/// again when the thread is built, in case the linker ignored the alignment.
+///
+/// The accessor panics if it is called twice, so the thread has one owner.
+/// The thread is never dropped, which is fine for the fixed tasks of a kernel.
+///
+/// ```
+/// use greenthread_future::{static_thread, yield_now};
+///
+/// static_thread!(WORKER -> u32, || {
+///     yield_now();
+///     1
+/// });
+///
+/// # async fn run() {
+/// assert_eq!(WORKER().await, 1);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! static_thread {
+    ($name:ident, $f:expr) => {
+        $crate::static_thread!($name -> (), $f);
+    };
+    ($name:ident -> $t:ty, $f:expr) => {
+        #[allow(non_snake_case)]
+        fn $name() -> &'static mut $crate::ThreadFuture<fn() -> $t, $t> {
+            type Thread = $crate::ThreadFuture<fn() -> $t, $t>;
+
+            #[repr(C, align(0x2000))]
+            struct Aligned(core::mem::MaybeUninit<Thread>);
+
+            // the TCB fits and the static is aligned, or it fails to compile
+            const _: [(); 0x2000] = [(); core::mem::size_of::<Thread>()];
+            const _: [(); 0x2000] = [(); core::mem::align_of::<Aligned>()];
+
+            static mut STORAGE: Aligned = Aligned(core::mem::MaybeUninit::uninit());
+            static TAKEN: core::sync::atomic::AtomicBool =
+                core::sync::atomic::AtomicBool::new(false);
+
+            assert!(
+                !TAKEN.swap(true, core::sync::atomic::Ordering::AcqRel),
+                "static thread is taken twice"
+            );
+            let f: fn() -> $t = $f;
+            // the storage is only reachable through here, and only once
+            unsafe {
+                let ptr = core::ptr::NonNull::new_unchecked(STORAGE.0.as_mut_ptr() as *mut u8);
+                Thread::from_raw(ptr, f)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::noop_waker;
+    use crate::yield_now;
+    use core::task::{Context, Poll};
+
+    static_thread!(COUNTER -> u32, || {
+        let mut n = 0;
+        for _ in 0..3 {
+            n += 1;
+            yield_now();
+        }
+        n
+    });
+
+    static_thread!(TAKEN_TWICE, yield_now);
+
+    #[test]
+    fn aligned_and_runnable() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let thread = COUNTER();
+        assert_eq!(thread as *mut _ as usize % 0x2000, 0);
+        let ret = loop {
+            if let Poll::Ready(ret) = thread.poll_unpin(&mut cx) {
+                break ret;
+            }
+        };
+        assert_eq!(ret, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "taken twice")]
+    fn take_twice() {
+        let _ = TAKEN_TWICE();
+        let _ = TAKEN_TWICE();
+    }
+}
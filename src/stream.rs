@@ -0,0 +1,162 @@
+//! Streams of values produced by a green thread.
+
+use crate::tick::ResumeArg;
+use crate::{park, ThreadFuture, TCB};
+use core::any::TypeId;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_core::Stream;
+
+/// A bounded FIFO of yielded values, in a buffer provided by the caller.
+struct Ring<'a, T> {
+    slots: &'a mut [Option<T>],
+    head: usize,
+    len: usize,
+}
+
+impl<T> Ring<'_, T> {
+    fn is_full(&self) -> bool {
+        self.len == self.slots.len()
+    }
+
+    fn push(&mut self, value: T) {
+        debug_assert!(!self.is_full());
+        let tail = (self.head + self.len) % self.slots.len();
+        self.slots[tail] = Some(value);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.slots[self.head].take();
+        self.head = (self.head + 1) % self.slots.len();
+        self.len -= 1;
+        value
+    }
+}
+
+/// A stream of the values given by a green thread to [`yield_value`].
+///
+/// The stream is the executor of the thread: the thread only runs inside
+/// `poll_next`, and only when the buffer is empty. It fills the buffer,
+/// then parks in `yield_value` until the consumer has drained it, so a
+/// fast producer never runs ahead of a slow consumer by more than the buffer.
+///
+/// If the thread parks for anything else, it is woken by the waker of the
+/// consumer task, which polls the stream again.
+pub struct GreenStream<'a, F, T> {
+    thread: Option<ThreadFuture<F, ()>>,
+    ring: Ring<'a, T>,
+}
+
+/// Create a stream of the values yielded by `f` on a green thread.
+///
+/// Up to `buffer.len()` values are produced ahead of the consumer.
+/// Panics if `buffer` is empty.
+pub fn green_stream<F, T>(f: F, buffer: &mut [Option<T>]) -> GreenStream<'_, F, T>
+where
+    F: Send + 'static + Unpin + FnOnce(),
+    T: Send + 'static,
+{
+    assert!(!buffer.is_empty(), "empty stream buffer");
+    GreenStream {
+        thread: Some(ThreadFuture::from(f)),
+        ring: Ring {
+            slots: buffer,
+            head: 0,
+            len: 0,
+        },
+    }
+}
+
+/// Give a value to the [`GreenStream`] running the current thread.
+///
+/// Parks the thread while the buffer of the stream is full.
+/// Panics if the thread is not run by a stream of `T`.
+pub fn yield_value<T: 'static>(value: T) {
+    let mut value = Some(value);
+    loop {
+        unsafe {
+            // type `F` and `T` do not matter
+            let tcb = TCB::<fn(), ()>::current();
+            assert!(!tcb.hints.resume_arg.is_null(), "not in a green stream");
+            let type_id = ResumeArg::type_id_at(tcb.hints.resume_arg);
+            assert!(
+                type_id == TypeId::of::<*mut Ring<'static, T>>(),
+                "yielded a value of another type"
+            );
+            let arg = &*(tcb.hints.resume_arg as *const ResumeArg<*mut Ring<'static, T>>);
+            let ring = &mut *arg.value.unwrap();
+            if !ring.is_full() {
+                ring.push(value.take().unwrap());
+                return;
+            }
+        }
+        park();
+    }
+}
+
+impl<F, T> Stream for GreenStream<'_, F, T>
+where
+    F: Send + 'static + Unpin + FnOnce(),
+    T: Send + 'static,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(value) = this.ring.pop() {
+                return Poll::Ready(Some(value));
+            }
+            let thread = match this.thread.as_mut() {
+                Some(thread) => thread,
+                None => return Poll::Ready(None),
+            };
+            // the lifetime is erased to match the type expected by `yield_value`
+            let ring = &mut this.ring as *mut Ring<'_, T> as *mut Ring<'static, T>;
+            match thread.resume_with(cx, ring) {
+                Poll::Ready(()) => this.thread = None,
+                // parked on a full buffer, or yielded with nothing
+                Poll::Pending if this.ring.len > 0 || thread.is_yielded() => {}
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::noop_waker;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn backpressure() {
+        static PRODUCED: AtomicUsize = AtomicUsize::new(0);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut buffer = [None, None];
+        let mut stream = green_stream(
+            || {
+                for i in 0..10u32 {
+                    yield_value(i);
+                    PRODUCED.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+            &mut buffer,
+        );
+        let mut consumed = 0;
+        while let Poll::Ready(Some(value)) = Pin::new(&mut stream).poll_next(&mut cx) {
+            assert_eq!(value, consumed);
+            consumed += 1;
+            // the producer parks on a full buffer instead of running ahead
+            assert!(PRODUCED.load(Ordering::SeqCst) <= consumed as usize + 2);
+        }
+        assert_eq!(consumed, 10);
+        assert_eq!(PRODUCED.load(Ordering::SeqCst), 10);
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(None));
+    }
+}
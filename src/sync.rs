@@ -0,0 +1,780 @@
+//! Synchronization primitives between green threads.
+//!
+//! They block by parking the current thread, and never allocate:
+//! a blocked thread is linked into a wait queue by a node on its own stack.
+//! The node is unlinked when the thread unwinds out of the wait, or when the
+//! thread is dropped while blocked, so the queue never holds a dangling node.
+
+use crate::{current_waker, park, TCB};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::Waker;
+
+/// A spin lock protecting short critical sections.
+///
+/// It must never be held across a switch.
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+pub(crate) struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A node of [`WaitQueue`], living on the stack of a blocked thread.
+pub(crate) struct Waiter<T> {
+    /// Waker of the blocked thread. Taken when it is notified.
+    waker: Option<Waker>,
+    /// Data handed over to or from the blocked thread.
+    pub data: T,
+    next: *mut Waiter<T>,
+}
+
+impl<T> Waiter<T> {
+    /// Create a node for the current thread.
+    pub fn new(data: T) -> Self {
+        Waiter {
+            waker: Some(current_waker()),
+            data,
+            next: null_mut(),
+        }
+    }
+
+    /// Whether the thread has been notified.
+    pub fn notified(&self) -> bool {
+        self.waker.is_none()
+    }
+
+    /// Mark the thread notified, returning the waker to wake it.
+    ///
+    /// The waker should be called after releasing the lock of the queue,
+    /// since the node may be gone as soon as the thread sees it notified.
+    pub fn notify(&mut self) -> Waker {
+        self.waker.take().expect("notified twice")
+    }
+}
+
+/// An intrusive FIFO queue of blocked threads.
+///
+/// It must be protected by a lock, and each node must stay in place
+/// until it is popped or removed from the queue.
+pub(crate) struct WaitQueue<T> {
+    head: *mut Waiter<T>,
+    tail: *mut Waiter<T>,
+}
+
+unsafe impl<T: Send> Send for WaitQueue<T> {}
+
+impl<T> WaitQueue<T> {
+    pub const fn new() -> Self {
+        WaitQueue {
+            head: null_mut(),
+            tail: null_mut(),
+        }
+    }
+
+    /// Push a node to the back.
+    ///
+    /// # Safety
+    ///
+    /// The node must not move or be dropped until it is popped or removed.
+    pub unsafe fn push_back(&mut self, node: *mut Waiter<T>) {
+        (*node).next = null_mut();
+        if self.tail.is_null() {
+            self.head = node;
+        } else {
+            (*self.tail).next = node;
+        }
+        self.tail = node;
+    }
+
+    /// Unlink a node, wherever it is. Returns whether it was in the queue.
+    pub fn remove(&mut self, node: *mut Waiter<T>) -> bool {
+        let mut prev: *mut Waiter<T> = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() {
+            let next = unsafe { (*cur).next };
+            if cur == node {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    unsafe { (*prev).next = next };
+                }
+                if self.tail == node {
+                    self.tail = prev;
+                }
+                return true;
+            }
+            prev = cur;
+            cur = next;
+        }
+        false
+    }
+
+    /// Pop a node from the front.
+    pub fn pop_front(&mut self) -> Option<&mut Waiter<T>> {
+        if self.head.is_null() {
+            return None;
+        }
+        let node = unsafe { &mut *self.head };
+        self.head = node.next;
+        if self.head.is_null() {
+            self.tail = null_mut();
+        }
+        Some(node)
+    }
+}
+
+/// The type-erased record of a thread blocked in `wait`.
+///
+/// The TCB points to it while the thread is blocked, so that dropping the
+/// thread can unlink its node.
+#[repr(C)]
+pub(crate) struct WaitLink {
+    unlink: unsafe fn(*mut WaitLink),
+}
+
+impl WaitLink {
+    /// Unlink the node of the blocked thread, as if it unwound out of `wait`.
+    ///
+    /// # Safety
+    ///
+    /// The thread is not running, and its stack is still in place.
+    pub unsafe fn unlink(link: *mut WaitLink) {
+        ((*link).unlink)(link)
+    }
+}
+
+/// A node of a blocked thread with what it takes to unlink it.
+#[repr(C)]
+struct Blocked<'a, T, U, Q, A>
+where
+    Q: Fn(&mut U) -> &mut WaitQueue<T>,
+    A: FnOnce(),
+{
+    /// The first field, so that a pointer to it points to the whole.
+    link: WaitLink,
+    /// The field of the TCB pointing to `link`.
+    slot: *mut *mut WaitLink,
+    lock: &'a SpinLock<U>,
+    queue: Q,
+    /// Pass on what the thread was handed, if it is notified but never returns.
+    abandon: Option<A>,
+    node: Waiter<T>,
+}
+
+impl<T, U, Q, A> Blocked<'_, T, U, Q, A>
+where
+    Q: Fn(&mut U) -> &mut WaitQueue<T>,
+    A: FnOnce(),
+{
+    unsafe fn unlink_erased(link: *mut WaitLink) {
+        core::ptr::drop_in_place(link as *mut Self);
+    }
+
+    /// Return the data of the notified node.
+    fn finish(mut self) -> T {
+        self.abandon = None;
+        unsafe {
+            *self.slot = null_mut();
+            let data = core::ptr::read(&self.node.data);
+            // the node is unlinked and its waker is taken, so nothing else is left
+            core::mem::forget(self);
+            data
+        }
+    }
+}
+
+impl<T, U, Q, A> Drop for Blocked<'_, T, U, Q, A>
+where
+    Q: Fn(&mut U) -> &mut WaitQueue<T>,
+    A: FnOnce(),
+{
+    fn drop(&mut self) {
+        unsafe { *self.slot = null_mut() };
+        let mut guard = self.lock.lock();
+        if !self.node.notified() {
+            (self.queue)(&mut *guard).remove(&mut self.node);
+            return;
+        }
+        drop(guard);
+        if let Some(abandon) = self.abandon.take() {
+            abandon();
+        }
+    }
+}
+
+/// Block the current thread in `queue` until it is notified.
+///
+/// Returns the data of the node after being notified. If the thread unwinds
+/// out of it or is dropped meanwhile, the node is unlinked, or `abandon` is
+/// called if it has been notified already, to pass on what it was handed.
+fn wait<T, U, Q, A>(
+    lock: &SpinLock<U>,
+    mut guard: SpinLockGuard<'_, U>,
+    queue: Q,
+    data: T,
+    abandon: A,
+) -> T
+where
+    Q: Fn(&mut U) -> &mut WaitQueue<T>,
+    A: FnOnce(),
+{
+    let slot = unsafe {
+        // type `F` and `T` do not matter
+        &mut TCB::<fn(), ()>::current().waiting as *mut *mut WaitLink
+    };
+    let mut blocked = Blocked {
+        link: WaitLink {
+            unlink: Blocked::<T, U, Q, A>::unlink_erased,
+        },
+        slot,
+        lock,
+        queue,
+        abandon: Some(abandon),
+        node: Waiter::new(data),
+    };
+    unsafe {
+        (blocked.queue)(&mut *guard).push_back(&mut blocked.node);
+        *slot = &mut blocked.link;
+    }
+    drop(guard);
+    loop {
+        park();
+        // the node is modified with the lock held
+        let guard = lock.lock();
+        if blocked.node.notified() {
+            drop(guard);
+            return blocked.finish();
+        }
+    }
+}
+
+/// An unbuffered channel between green threads.
+///
+/// Both `send` and `recv` block the current thread until a counterpart arrives,
+/// then the value is handed over directly through a slot on the stack of
+/// the thread who came first. Any number of senders and receivers can share it.
+pub struct Rendezvous<T> {
+    inner: SpinLock<RendezvousInner<T>>,
+}
+
+struct RendezvousInner<T> {
+    /// Blocked senders with their values.
+    senders: WaitQueue<Option<T>>,
+    /// Blocked receivers with their empty slots.
+    receivers: WaitQueue<Option<T>>,
+}
+
+impl<T> Rendezvous<T> {
+    /// Create a new channel.
+    pub const fn new() -> Self {
+        Rendezvous {
+            inner: SpinLock::new(RendezvousInner {
+                senders: WaitQueue::new(),
+                receivers: WaitQueue::new(),
+            }),
+        }
+    }
+}
+
+impl<T: Send> Rendezvous<T> {
+    /// Send a value, blocking until a receiver takes it.
+    ///
+    /// Must be called inside a green thread.
+    pub fn send(&self, value: T) {
+        let mut inner = self.inner.lock();
+        if let Some(receiver) = inner.receivers.pop_front() {
+            receiver.data = Some(value);
+            let waker = receiver.notify();
+            drop(inner);
+            waker.wake();
+            return;
+        }
+        wait(&self.inner, inner, |inner| &mut inner.senders, Some(value), || {});
+    }
+
+    /// Receive a value, blocking until a sender gives one.
+    ///
+    /// Must be called inside a green thread.
+    pub fn recv(&self) -> T {
+        let mut inner = self.inner.lock();
+        if let Some(sender) = inner.senders.pop_front() {
+            let value = sender.data.take().unwrap();
+            let waker = sender.notify();
+            drop(inner);
+            waker.wake();
+            return value;
+        }
+        wait(&self.inner, inner, |inner| &mut inner.receivers, None, || {}).unwrap()
+    }
+}
+
+impl<T> Default for Rendezvous<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A mutual exclusion lock between green threads.
+///
+/// A thread which finds it locked parks in a wait queue instead of spinning.
+/// Unlocking hands the lock over to the first waiter, so waiters are served
+/// in order and none of them starves.
+pub struct GreenMutex<T> {
+    inner: SpinLock<GreenMutexInner>,
+    data: UnsafeCell<T>,
+}
+
+struct GreenMutexInner {
+    locked: bool,
+    waiters: WaitQueue<()>,
+}
+
+unsafe impl<T: Send> Send for GreenMutex<T> {}
+unsafe impl<T: Send> Sync for GreenMutex<T> {}
+
+/// The guard of a locked [`GreenMutex`]. Dropping it unlocks the mutex.
+pub struct GreenMutexGuard<'a, T> {
+    mutex: &'a GreenMutex<T>,
+}
+
+impl<T> GreenMutex<T> {
+    /// Create a new mutex in the unlocked state.
+    pub const fn new(data: T) -> Self {
+        GreenMutex {
+            inner: SpinLock::new(GreenMutexInner {
+                locked: false,
+                waiters: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Lock the mutex, blocking until it is available.
+    ///
+    /// Must be called inside a green thread, unless it is unlocked.
+    pub fn lock(&self) -> GreenMutexGuard<'_, T> {
+        let mut inner = self.inner.lock();
+        if inner.locked {
+            // the lock is handed over by the unlocker
+            wait(&self.inner, inner, |inner| &mut inner.waiters, (), || self.unlock());
+        } else {
+            inner.locked = true;
+        }
+        GreenMutexGuard { mutex: self }
+    }
+
+    /// Lock the mutex if it is unlocked, without blocking.
+    pub fn try_lock(&self) -> Option<GreenMutexGuard<'_, T>> {
+        let mut inner = self.inner.lock();
+        if inner.locked {
+            return None;
+        }
+        inner.locked = true;
+        Some(GreenMutexGuard { mutex: self })
+    }
+
+    /// Hand the lock over to the first waiter, or unlock it if there is none.
+    fn unlock(&self) {
+        let mut inner = self.inner.lock();
+        match inner.waiters.pop_front() {
+            Some(waiter) => {
+                let waker = waiter.notify();
+                drop(inner);
+                waker.wake();
+            }
+            None => inner.locked = false,
+        }
+    }
+}
+
+impl<T: Default> Default for GreenMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Deref for GreenMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for GreenMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for GreenMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable for green threads holding a [`GreenMutex`].
+///
+/// Waiters park in a wait queue on their own stacks. A waiter may wake up
+/// spuriously, so it should check its condition again in a loop.
+pub struct GreenCondvar {
+    inner: SpinLock<GreenCondvarInner>,
+}
+
+struct GreenCondvarInner {
+    waiters: WaitQueue<()>,
+}
+
+impl GreenCondvar {
+    /// Create a new condition variable.
+    pub const fn new() -> Self {
+        GreenCondvar {
+            inner: SpinLock::new(GreenCondvarInner {
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Unlock the mutex of `guard` and block until notified, then lock it again.
+    ///
+    /// Must be called inside a green thread.
+    pub fn wait<'a, T>(&self, guard: GreenMutexGuard<'a, T>) -> GreenMutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let inner = self.inner.lock();
+        // a notifier spins until the thread is queued, so no notification is lost
+        drop(guard);
+        wait(&self.inner, inner, |inner| &mut inner.waiters, (), || self.notify_one());
+        mutex.lock()
+    }
+
+    /// Wake up the first waiting thread, if any.
+    pub fn notify_one(&self) {
+        let mut inner = self.inner.lock();
+        if let Some(waiter) = inner.waiters.pop_front() {
+            let waker = waiter.notify();
+            drop(inner);
+            waker.wake();
+        }
+    }
+
+    /// Wake up all waiting threads.
+    pub fn notify_all(&self) {
+        let mut inner = self.inner.lock();
+        // the wakers are moved out of the nodes, so waking them with the
+        // lock held is fine, and threads waiting again are not woken twice
+        while let Some(waiter) = inner.waiters.pop_front() {
+            waiter.notify().wake();
+        }
+    }
+}
+
+impl Default for GreenCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A counting semaphore between green threads.
+///
+/// A thread which finds no permit parks in a wait queue. A released permit
+/// is handed over to the first waiter, so waiters are served in order.
+pub struct GreenSemaphore {
+    /// Permits available. It only grows with the lock of `waiters` held,
+    /// so a thread which finds none under the lock can safely queue itself.
+    permits: AtomicUsize,
+    waiters: SpinLock<WaitQueue<()>>,
+}
+
+/// A permit of a [`GreenSemaphore`]. Dropping it releases the permit.
+pub struct GreenSemaphorePermit<'a> {
+    semaphore: &'a GreenSemaphore,
+}
+
+impl GreenSemaphore {
+    /// Create a semaphore with `permits` permits.
+    pub const fn new(permits: usize) -> Self {
+        GreenSemaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: SpinLock::new(WaitQueue::new()),
+        }
+    }
+
+    /// Take a permit, blocking until one is available.
+    ///
+    /// Must be called inside a green thread, unless a permit is available.
+    pub fn acquire(&self) -> GreenSemaphorePermit<'_> {
+        if let Some(permit) = self.try_acquire() {
+            return permit;
+        }
+        let waiters = self.waiters.lock();
+        if let Some(permit) = self.try_acquire() {
+            return permit;
+        }
+        // the permit is handed over by the releaser
+        wait(&self.waiters, waiters, |waiters| waiters, (), || self.release());
+        GreenSemaphorePermit { semaphore: self }
+    }
+
+    /// Take a permit if one is available, without blocking.
+    pub fn try_acquire(&self) -> Option<GreenSemaphorePermit<'_>> {
+        let mut permits = self.permits.load(Ordering::Acquire);
+        while permits != 0 {
+            match self.permits.compare_exchange_weak(
+                permits,
+                permits - 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(GreenSemaphorePermit { semaphore: self }),
+                Err(current) => permits = current,
+            }
+        }
+        None
+    }
+
+    /// Add a permit, handing it over to the first waiter if any.
+    ///
+    /// Dropping a permit calls it.
+    pub fn release(&self) {
+        let mut waiters = self.waiters.lock();
+        match waiters.pop_front() {
+            Some(waiter) => {
+                let waker = waiter.notify();
+                drop(waiters);
+                waker.wake();
+            }
+            None => {
+                self.permits.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Number of permits available now.
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for GreenSemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::noop_waker;
+    use crate::{yield_now, ThreadFuture};
+    use core::task::Context;
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn rendezvous() {
+        let chan = Arc::new(Rendezvous::new());
+        let tx = chan.clone();
+        let sender = tokio::spawn(ThreadFuture::from(move || {
+            for i in 1..=3u32 {
+                tx.send(i);
+            }
+        }));
+        let receiver = tokio::spawn(ThreadFuture::from(move || {
+            let mut sum = 0;
+            for _ in 0..3 {
+                sum += chan.recv();
+                yield_now();
+            }
+            sum
+        }));
+        sender.await.unwrap();
+        assert_eq!(receiver.await.unwrap(), 6);
+    }
+
+    #[test]
+    fn mutex_contention() {
+        static MUTEX: GreenMutex<u32> = GreenMutex::new(0);
+        fn worker() {
+            for _ in 0..3 {
+                let mut count = MUTEX.lock();
+                *count += 1;
+                // switch out with the lock held, so the other thread contends
+                yield_now();
+            }
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut threads = [
+            Box::new(ThreadFuture::from(worker as fn())),
+            Box::new(ThreadFuture::from(worker as fn())),
+        ];
+        assert!(threads[0].poll_unpin(&mut cx).is_pending());
+        // the second thread parks on the held lock, rather than spinning
+        assert!(threads[1].poll_unpin(&mut cx).is_pending());
+        assert!(!threads[1].is_yielded());
+        assert!(MUTEX.try_lock().is_none());
+
+        let mut done = [false; 2];
+        while !done.iter().all(|&d| d) {
+            for (thread, done) in threads.iter_mut().zip(done.iter_mut()) {
+                if !*done {
+                    *done = thread.poll_unpin(&mut cx).is_ready();
+                }
+            }
+        }
+        assert_eq!(*MUTEX.lock(), 6);
+    }
+
+    #[test]
+    fn drop_blocked_thread() {
+        static MUTEX: GreenMutex<()> = GreenMutex::new(());
+        fn holder() {
+            let _guard = MUTEX.lock();
+            yield_now();
+        }
+        fn waiter() {
+            let _guard = MUTEX.lock();
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut holder = Box::new(ThreadFuture::from(holder as fn()));
+        let mut first = Box::new(ThreadFuture::from(waiter as fn()));
+        let mut second = Box::new(ThreadFuture::from(waiter as fn()));
+        assert!(holder.poll_unpin(&mut cx).is_pending());
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        assert!(second.poll_unpin(&mut cx).is_pending());
+        // the node of the first is unlinked, so the lock goes to the second
+        drop(first);
+        assert!(holder.poll_unpin(&mut cx).is_ready());
+        assert!(MUTEX.try_lock().is_none());
+        // the second never takes the lock it was handed, so it is passed on
+        drop(second);
+        assert!(MUTEX.try_lock().is_some());
+    }
+
+    #[tokio::test]
+    async fn bounded_buffer() {
+        const CAPACITY: usize = 2;
+        struct Buffer {
+            queue: GreenMutex<VecDeque<u32>>,
+            not_full: GreenCondvar,
+            not_empty: GreenCondvar,
+        }
+        let buffer = Arc::new(Buffer {
+            queue: GreenMutex::new(VecDeque::new()),
+            not_full: GreenCondvar::new(),
+            not_empty: GreenCondvar::new(),
+        });
+        let tx = buffer.clone();
+        let producer = tokio::spawn(ThreadFuture::from(move || {
+            for i in 0..10 {
+                let mut queue = tx.queue.lock();
+                while queue.len() == CAPACITY {
+                    queue = tx.not_full.wait(queue);
+                }
+                queue.push_back(i);
+                drop(queue);
+                tx.not_empty.notify_one();
+            }
+        }));
+        let consumer = tokio::spawn(ThreadFuture::from(move || {
+            let mut received = Vec::new();
+            while received.len() < 10 {
+                let mut queue = buffer.queue.lock();
+                while queue.is_empty() {
+                    queue = buffer.not_empty.wait(queue);
+                }
+                assert!(queue.len() <= CAPACITY);
+                received.push(queue.pop_front().unwrap());
+                drop(queue);
+                buffer.not_full.notify_one();
+            }
+            received
+        }));
+        producer.await.unwrap();
+        assert_eq!(consumer.await.unwrap(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn semaphore_limits_in_flight() {
+        static SEMAPHORE: GreenSemaphore = GreenSemaphore::new(2);
+        static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+        static MAX: AtomicUsize = AtomicUsize::new(0);
+        fn worker() {
+            for _ in 0..3 {
+                let _permit = SEMAPHORE.acquire();
+                let n = IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+                if n > MAX.load(Ordering::SeqCst) {
+                    MAX.store(n, Ordering::SeqCst);
+                }
+                yield_now();
+                yield_now();
+                IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut threads: Vec<_> = (0..4)
+            .map(|_| Box::new(ThreadFuture::from(worker as fn())))
+            .collect();
+        let mut done = [false; 4];
+        while !done.iter().all(|&d| d) {
+            for (thread, done) in threads.iter_mut().zip(done.iter_mut()) {
+                if !*done {
+                    *done = thread.poll_unpin(&mut cx).is_ready();
+                }
+            }
+        }
+        assert_eq!(MAX.load(Ordering::SeqCst), 2);
+        assert_eq!(SEMAPHORE.available_permits(), 2);
+    }
+}
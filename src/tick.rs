@@ -0,0 +1,115 @@
+//! Values passed to a thread each time it is resumed.
+
+use crate::{park, ThreadFuture, TCB};
+use core::any::TypeId;
+use core::task::{Context, Poll};
+
+/// A value for a thread blocked in [`yield_tick`], on the executor stack.
+///
+/// `type_id` is first, so the type can be checked by [`ResumeArg::type_id_at`]
+/// before the pointer is cast to a `ResumeArg` of the expected type.
+#[repr(C)]
+pub(crate) struct ResumeArg<R> {
+    pub type_id: TypeId,
+    pub value: Option<R>,
+}
+
+impl ResumeArg<()> {
+    /// Get the type id of the `ResumeArg` of any type at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live `ResumeArg`.
+    pub unsafe fn type_id_at(ptr: *const ()) -> TypeId {
+        *(ptr as *const TypeId)
+    }
+}
+
+/// Block the current thread until the executor resumes it with a value,
+/// then return the value.
+///
+/// The executor passes the value by [`ThreadFuture::resume_with`], like the
+/// argument of `Generator::resume`. Polls without a value are ignored.
+/// Panics if the value is not of type `R`.
+///
+/// Must be called inside a green thread.
+pub fn yield_tick<R: 'static>() -> R {
+    loop {
+        park();
+        unsafe {
+            // type `F` and `T` do not matter
+            let tcb = TCB::<fn(), ()>::current();
+            if tcb.hints.resume_arg.is_null() {
+                continue;
+            }
+            assert!(
+                ResumeArg::type_id_at(tcb.hints.resume_arg) == TypeId::of::<R>(),
+                "resumed with a value of another type"
+            );
+            let arg = &mut *(tcb.hints.resume_arg as *mut ResumeArg<R>);
+            if let Some(value) = arg.value.take() {
+                return value;
+            }
+        }
+    }
+}
+
+impl<F, T> ThreadFuture<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    /// Poll the thread, passing `value` to its pending [`yield_tick`].
+    ///
+    /// If the thread does not take the value in this poll, it is dropped.
+    pub fn resume_with<R: 'static>(&mut self, cx: &mut Context<'_>, value: R) -> Poll<T> {
+        let mut arg = ResumeArg {
+            type_id: TypeId::of::<R>(),
+            value: Some(value),
+        };
+        unsafe {
+            (*self.tcb).hints.resume_arg = &mut arg as *mut ResumeArg<R> as *mut ();
+        }
+        let ret = self.poll_unpin(cx);
+        unsafe {
+            (*self.tcb).hints.resume_arg = core::ptr::null_mut();
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::noop_waker;
+
+    #[test]
+    fn resume_with_ticks() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = ThreadFuture::from(|| {
+            let mut ticks = [0u32; 3];
+            for tick in ticks.iter_mut() {
+                *tick = yield_tick();
+            }
+            ticks
+        });
+        // run until the first `yield_tick`
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        // a poll without a value does not resume it
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        assert!(thread.resume_with(&mut cx, 1u32).is_pending());
+        assert!(thread.resume_with(&mut cx, 2u32).is_pending());
+        assert_eq!(thread.resume_with(&mut cx, 3u32), Poll::Ready([1, 2, 3]));
+    }
+
+    #[test]
+    #[should_panic(expected = "resumed with a value of another type")]
+    fn resume_with_wrong_type() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut thread = ThreadFuture::from(yield_tick::<u32>);
+        assert!(thread.poll_unpin(&mut cx).is_pending());
+        let _ = thread.resume_with(&mut cx, [0u64; 4]);
+    }
+}
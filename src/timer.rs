@@ -0,0 +1,147 @@
+//! Deadlines on threads, using a timer provided by the runtime.
+
+use crate::ThreadFuture;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+/// A timer provided by the runtime.
+pub trait Timer {
+    /// Future which completes after a duration.
+    type Sleep: Future<Output = ()>;
+
+    /// Create a future which completes after `dur`.
+    fn sleep(&self, dur: Duration) -> Self::Sleep;
+}
+
+/// Error returned when a thread does not exit before its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+/// The timer of the tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    type Sleep = tokio::time::Delay;
+
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        tokio::time::delay_for(dur)
+    }
+}
+
+impl<F, T> ThreadFuture<F, T>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+{
+    /// Drive the thread, giving up if it does not exit within `dur`.
+    ///
+    /// On timeout the thread is cancelled by dropping it at its yield point.
+    /// Its stack is discarded without unwinding.
+    /// If the thread exits in the same poll as the timer fires, it wins.
+    pub fn run_with_deadline<Tm: Timer>(
+        self,
+        timer: &Tm,
+        dur: Duration,
+    ) -> impl Future<Output = Result<T, Timeout>> {
+        Deadline {
+            thread: Some(self),
+            sleep: timer.sleep(dur),
+        }
+    }
+
+    /// Drive the thread on the timer of tokio, giving up if it does not exit
+    /// within `dur`.
+    ///
+    /// It is [`run_with_deadline`](Self::run_with_deadline) with [`TokioTimer`],
+    /// so it must be polled inside a tokio runtime with the timer enabled.
+    #[cfg(feature = "tokio")]
+    pub fn timeout(self, dur: Duration) -> impl Future<Output = Result<T, Timeout>> {
+        self.run_with_deadline(&TokioTimer, dur)
+    }
+}
+
+/// Future for [`ThreadFuture::run_with_deadline`].
+struct Deadline<F, T, S> {
+    thread: Option<ThreadFuture<F, T>>,
+    sleep: S,
+}
+
+impl<F, T, S> Future for Deadline<F, T, S>
+where
+    F: Send + 'static + Unpin + FnOnce() -> T,
+    T: Send + 'static + Unpin,
+    S: Future<Output = ()>,
+{
+    type Output = Result<T, Timeout>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `sleep` is pinned together with `self`, while `thread` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+        let thread = this.thread.as_mut().expect("polled after completion");
+        if let Poll::Ready(ret) = thread.poll_unpin(cx) {
+            this.thread = None;
+            return Poll::Ready(Ok(ret));
+        }
+        if let Poll::Ready(()) = unsafe { Pin::new_unchecked(&mut this.sleep) }.poll(cx) {
+            // cancel the thread
+            this.thread = None;
+            return Poll::Ready(Err(Timeout));
+        }
+        Poll::Pending
+    }
+}
+
+// they run on the `TokioTimer`
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use crate::{park, yield_now};
+
+    #[tokio::test]
+    async fn exit_in_time() {
+        let thread = ThreadFuture::from(|| {
+            for _ in 0..3 {
+                yield_now();
+            }
+            1u32
+        });
+        let ret = thread
+            .run_with_deadline(&TokioTimer, Duration::from_secs(10))
+            .await;
+        assert_eq!(ret, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let thread = ThreadFuture::from(|| loop {
+            park();
+        });
+        let ret = thread
+            .run_with_deadline(&TokioTimer, Duration::from_millis(10))
+            .await;
+        assert_eq!(ret, Err(Timeout));
+    }
+
+    #[tokio::test]
+    async fn tokio_timeout() {
+        let ret = ThreadFuture::from(|| {
+            yield_now();
+            1u32
+        })
+        .timeout(Duration::from_secs(10))
+        .await;
+        assert_eq!(ret, Ok(1));
+
+        let ret = ThreadFuture::from(|| loop {
+            park();
+        })
+        .timeout(Duration::from_millis(10))
+        .await;
+        assert_eq!(ret, Err(Timeout));
+    }
+}
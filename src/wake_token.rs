@@ -0,0 +1,94 @@
+//! Waking threads from interrupt handlers.
+
+use crate::{current_waker, park};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+
+/// A token to wake a thread from an interrupt handler.
+///
+/// `Waker::wake` may lock or allocate inside the executor, which is not safe
+/// in an interrupt handler. The token splits it into two halves:
+///
+/// - [`wake`](WakeToken::wake) only sets an atomic flag. It is safe to call
+///   from an ISR, and so is setting the flag directly.
+/// - [`dispatch`](WakeToken::dispatch) forwards a pending flag to the cached
+///   waker. It must be called outside of interrupt context, e.g. in the idle
+///   loop of the executor.
+///
+/// The thread waits on the token by [`wait`](WakeToken::wait),
+/// which only returns after the flag is set.
+pub struct WakeToken {
+    flag: &'static AtomicBool,
+    waker: Waker,
+}
+
+/// Create a token for the current thread with the interrupt-side `flag`.
+///
+/// The flag is not cleared, so a wake-up before this call is not lost.
+pub fn current_wake_token(flag: &'static AtomicBool) -> WakeToken {
+    WakeToken {
+        flag,
+        waker: current_waker(),
+    }
+}
+
+impl WakeToken {
+    /// Mark the thread woken. Only touches the flag, so it is ISR-safe.
+    pub fn wake(&self) {
+        self.flag.store(true, Ordering::Release);
+    }
+
+    /// Call the waker if the flag is set. Returns whether it was called.
+    ///
+    /// It is not ISR-safe.
+    pub fn dispatch(&self) -> bool {
+        if self.flag.load(Ordering::Acquire) {
+            self.waker.wake_by_ref();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Park the current thread until the flag is set, then clear it.
+    ///
+    /// Wake-ups without the flag set are spurious and ignored.
+    /// Must be called inside the thread owning the token.
+    pub fn wait(&self) {
+        while !self.flag.swap(false, Ordering::Acquire) {
+            park();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadFuture;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn wake_from_isr() {
+        static FLAG: AtomicBool = AtomicBool::new(false);
+        let (tx, rx) = mpsc::channel();
+        // plays both the interrupt handler and the executor's idle loop
+        let isr = std::thread::spawn(move || {
+            let token: std::sync::Arc<WakeToken> = rx.recv().unwrap();
+            // a spurious wake-up does not end the wait
+            token.waker.wake_by_ref();
+            std::thread::sleep(Duration::from_millis(10));
+            assert!(!token.dispatch());
+            token.wake();
+            token.dispatch();
+        });
+        ThreadFuture::from(move || {
+            let token = std::sync::Arc::new(current_wake_token(&FLAG));
+            tx.send(token.clone()).unwrap();
+            token.wait();
+            assert!(!FLAG.load(Ordering::SeqCst));
+        })
+        .await;
+        isr.join().unwrap();
+    }
+}
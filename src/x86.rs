@@ -0,0 +1,278 @@
+/// Bytes reserved at the top of a new stack, above the initial context.
+///
+/// The thread enters `entry` by `ret`, and cdecl passes the argument on the
+/// stack above the return address, so we leave room for a fake return address
+/// and the argument here. The i386 System V ABI also requires `esp + 4` to be
+/// 16-byte aligned at function entry, which takes 12 more bytes.
+const STACK_TOP_RESERVED: usize = 20;
+
+/// Saved registers of a thread.
+#[repr(C)]
+#[derive(Debug)]
+struct ThreadContext {
+    /// Round the size up to a multiple of 16 bytes
+    _pad: [usize; 3],
+    ebx: usize,
+    esi: usize,
+    edi: usize,
+    ebp: usize,
+    eip: usize,
+}
+
+/// Offsets of the saved registers in `ThreadContext`.
+const CONTEXT_REGISTERS: &[(&str, usize)] = &[
+    ("ebx", 12),
+    ("esi", 16),
+    ("edi", 20),
+    ("ebp", 24),
+    ("eip", 28),
+];
+
+/// Offset of the program counter in `ThreadContext`.
+const CONTEXT_PC_OFFSET: usize = 28;
+
+/// Offset of the frame pointer in `ThreadContext`.
+const CONTEXT_FP_OFFSET: usize = 24;
+
+/// Offset of the entry of a new thread in its initial context.
+const CONTEXT_ENTRY_OFFSET: usize = 28;
+
+impl ThreadContext {
+    /// Switch context to another thread.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "C" fn switch(_ptr_ptr: *mut *mut Self) {
+        asm!(r#"
+        // push eip (by caller)
+        push ebp
+        push edi
+        push esi
+        push ebx
+        sub esp, 12
+
+        mov eax, [esp + 32]
+        mov ecx, [eax]
+        mov [eax], esp
+        mov esp, ecx
+
+        // the ABI requires DF clear at calls and returns,
+        // but a thread may have set it before switching out
+        cld
+
+        add esp, 12
+        pop ebx
+        pop esi
+        pop edi
+        pop ebp
+        // pop eip (by ret)
+        "# :::: "volatile" "intel");
+    }
+
+    /// Set value of program counter.
+    fn set_pc(&mut self, pc: usize) {
+        self.eip = pc;
+    }
+
+    /// Get value of program counter.
+    fn pc(&self) -> usize {
+        self.eip
+    }
+
+    /// Set value of frame pointer.
+    fn set_fp(&mut self, fp: usize) {
+        self.ebp = fp;
+    }
+
+    /// Make the initial context enter `pc` with `arg` as the first argument.
+    ///
+    /// The argument lives above the fake return address in the reserved bytes,
+    /// so it is only valid for the initial context of a new stack.
+    fn set_entry(&mut self, pc: usize, arg: usize) {
+        self.eip = pc;
+        unsafe {
+            let ret_addr = (self as *mut Self).add(1) as *mut usize;
+            ret_addr.add(1).write(arg);
+        }
+    }
+}
+
+/// Optional registers of a thread, swapped in `switch_ext` by its `RegSet`.
+///
+/// The i386 ABI has no callee-saved x87 or SSE registers, only their control
+/// words, so `FP` is the x87 control word and `VECTOR` is `mxcsr`, if SSE is
+/// enabled. `TLS` is empty, since the `gs` base can only be set by the OS.
+///
+/// `switch_ext` accesses the fields by offset: `fpu_cw` at 0 and `mxcsr` at 4.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ExtContext {
+    fpu_cw: u16,
+    #[cfg(target_feature = "sse")]
+    mxcsr: u32,
+}
+
+/// Swap `mxcsr` in `switch_ext`: save it to `[esp + 4]`, load it from `[esi + 4]`.
+#[cfg(target_feature = "sse")]
+macro_rules! swap_vector_in {
+    () => {
+        r#"
+        test edi, 8
+        jz 3f
+        stmxcsr [esp + 4]
+        ldmxcsr [esi + 4]
+    3:
+        "#
+    };
+}
+#[cfg(not(target_feature = "sse"))]
+macro_rules! swap_vector_in {
+    () => {
+        ""
+    };
+}
+
+/// Swap `mxcsr` back in `switch_ext`: save it to `[esi + 4]`, load it from `[esp + 4]`.
+#[cfg(target_feature = "sse")]
+macro_rules! swap_vector_out {
+    () => {
+        r#"
+        test edi, 8
+        jz 5f
+        stmxcsr [esi + 4]
+        ldmxcsr [esp + 4]
+    5:
+        "#
+    };
+}
+#[cfg(not(target_feature = "sse"))]
+macro_rules! swap_vector_out {
+    () => {
+        ""
+    };
+}
+
+impl ThreadContext {
+    /// Call the `switch` at `_switch` with the register groups in the bits of
+    /// `_regs` swapped: the executor ones are kept on this stack, and the
+    /// thread ones are loaded from `_ext` before and saved to it after.
+    ///
+    /// It is one asm block, so no compiled code runs between loading the
+    /// registers of the thread and switching to it.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "C" fn switch_ext(
+        _ptr_ptr: *mut *mut Self,
+        _ext: *mut ExtContext,
+        _regs: usize,
+        _switch: usize,
+    ) {
+        asm!(concat!(r#"
+        push ebp
+        push ebx
+        push esi
+        push edi
+        // the executor copy of `ExtContext`, which also keeps the stack
+        // 16-byte aligned at `call`
+        sub esp, 8
+
+        mov ebx, [esp + 28]
+        mov esi, [esp + 32]
+        mov edi, [esp + 36]
+        mov ebp, [esp + 40]
+
+        test edi, 2
+        jz 2f
+        fnstcw [esp]
+        fldcw [esi]
+    2:
+        "#, swap_vector_in!(), r#"
+        push ebx
+        call ebp
+        add esp, 4
+
+        test edi, 2
+        jz 4f
+        fnstcw [esi]
+        fldcw [esp]
+    4:
+        "#, swap_vector_out!(), r#"
+        add esp, 8
+        pop edi
+        pop esi
+        pop ebx
+        pop ebp
+        "#) :::: "volatile" "intel");
+    }
+}
+
+impl ExtContext {
+    /// Save the register groups in `regs` from the CPU, which a new thread
+    /// starts with.
+    #[inline(always)]
+    unsafe fn save(&mut self, regs: RegSet) {
+        if regs.contains(RegSet::FP) {
+            asm!("fnstcw [$0]" :: "r"(&mut self.fpu_cw) : "memory" : "volatile" "intel");
+        }
+        #[cfg(target_feature = "sse")]
+        if regs.contains(RegSet::VECTOR) {
+            asm!("stmxcsr [$0]" :: "r"(&mut self.mxcsr) : "memory" : "volatile" "intel");
+        }
+    }
+}
+
+/// Number of registers set by `switch_marked`.
+#[cfg(test)]
+const CALLEE_SAVED: usize = 4;
+
+/// Set callee-saved registers to `base + i` in the order of `ThreadContext`,
+/// call `switch` with `ptr_ptr`, then store the registers found after
+/// switching back to `out`. It is used by the conformance tests.
+#[cfg(test)]
+#[naked]
+#[inline(never)]
+unsafe extern "C" fn switch_marked(
+    _ptr_ptr: *mut *mut ThreadContext,
+    _out: *mut usize,
+    _base: usize,
+    _switch: usize,
+) {
+    asm!(r#"
+    push ebp
+    push edi
+    push esi
+    push ebx
+
+    mov eax, [esp + 20]
+    mov edx, [esp + 28]
+    mov ecx, [esp + 32]
+    // keep the stack 16-byte aligned at `call`
+    sub esp, 8
+    push eax
+
+    mov ebx, edx
+    lea esi, [edx + 1]
+    lea edi, [edx + 2]
+    lea ebp, [edx + 3]
+    call ecx
+    add esp, 12
+
+    mov eax, [esp + 24]
+    mov [eax], ebx
+    mov [eax + 4], esi
+    mov [eax + 8], edi
+    mov [eax + 12], ebp
+
+    pop ebx
+    pop esi
+    pop edi
+    pop ebp
+    "# :::: "volatile" "intel");
+}
+
+/// Get stack pointer.
+#[inline(always)]
+unsafe fn stack_pointer() -> usize {
+    let mut sp: usize;
+    asm!("" : "={esp}"(sp));
+    sp
+}
@@ -1,7 +1,16 @@
+/// Bytes reserved at the top of a new stack, above the initial context.
+///
+/// The SysV ABI requires `rsp + 8` to be 16-byte aligned at function entry,
+/// as if a return address had just been pushed by `call`. The thread enters
+/// `entry` by `ret` instead, so we leave room for that return address here.
+const STACK_TOP_RESERVED: usize = 8;
+
 /// Saved registers of a thread.
 #[repr(C)]
 #[derive(Debug)]
 struct ThreadContext {
+    /// Round the size up to a multiple of 16 bytes
+    _pad: usize,
     rbx: usize,
     rbp: usize,
     r12: usize,
@@ -11,6 +20,26 @@ struct ThreadContext {
     rip: usize,
 }
 
+/// Offsets of the saved registers in `ThreadContext`.
+const CONTEXT_REGISTERS: &[(&str, usize)] = &[
+    ("rbx", 8),
+    ("rbp", 16),
+    ("r12", 24),
+    ("r13", 32),
+    ("r14", 40),
+    ("r15", 48),
+    ("rip", 56),
+];
+
+/// Offset of the program counter in `ThreadContext`.
+const CONTEXT_PC_OFFSET: usize = 56;
+
+/// Offset of the frame pointer in `ThreadContext`.
+const CONTEXT_FP_OFFSET: usize = 16;
+
+/// Offset of the entry of a new thread in its initial context.
+const CONTEXT_ENTRY_OFFSET: usize = 24;
+
 impl ThreadContext {
     /// Switch context to another thread.
     #[naked]
@@ -24,11 +53,17 @@ impl ThreadContext {
         push r12
         push rbp
         push rbx
+        sub rsp, 8
 
         mov rax, [rdi]
         mov [rdi], rsp
         mov rsp, rax
 
+        // the ABI requires DF clear at calls and returns,
+        // but a thread may have set it before switching out
+        cld
+
+        add rsp, 8
         pop rbx
         pop rbp
         pop r12
@@ -36,13 +71,241 @@ impl ThreadContext {
         pop r14
         pop r15
         // pop rip (by ret)
-        "# :::: "volatile" "intel" "alignstack");
+        "# :::: "volatile" "intel");
     }
 
     /// Set value of program counter.
     fn set_pc(&mut self, pc: usize) {
         self.rip = pc;
     }
+
+    /// Get value of program counter.
+    fn pc(&self) -> usize {
+        self.rip
+    }
+
+    /// Set value of frame pointer.
+    fn set_fp(&mut self, fp: usize) {
+        self.rbp = fp;
+    }
+
+    /// Make the initial context enter `pc` with `arg` in the first argument register.
+    ///
+    /// `switch` only restores callee-saved registers, so they are parked in
+    /// `r12` and `rbx`, and the first switch returns to `trampoline` to move
+    /// them into place.
+    fn set_entry(&mut self, pc: usize, arg: usize) {
+        self.rip = Self::trampoline as usize;
+        self.r12 = pc;
+        self.rbx = arg;
+    }
+
+    /// Call the entry of a new thread set by `set_entry`.
+    ///
+    /// It is entered by `ret` with the stack aligned as at function entry,
+    /// so it jumps rather than calls.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "sysv64" fn trampoline() {
+        asm!(r#"
+        mov rdi, rbx
+        jmp r12
+        "# :::: "volatile" "intel");
+    }
+}
+
+/// Optional registers of a thread, swapped in `switch_ext` by its `RegSet`.
+///
+/// The SysV ABI has no callee-saved x87 or SSE registers, only their control
+/// words, so `FP` is the x87 control word and `VECTOR` is `mxcsr`. `TLS` is the
+/// `fs` base, which is only saved with the `fsgsbase` target feature, since the
+/// instructions fault unless the OS has enabled them.
+///
+/// `switch_ext` accesses the fields by offset: `mxcsr` at 0, `fpu_cw` at 4
+/// and `fs_base` at 8.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ExtContext {
+    mxcsr: u32,
+    fpu_cw: u16,
+    #[cfg(target_feature = "fsgsbase")]
+    fs_base: usize,
+}
+
+/// Swap the `fs` base in `switch_ext`: save it to `[rsp + 8]`, load it from `[rbx + 8]`.
+#[cfg(target_feature = "fsgsbase")]
+macro_rules! swap_tls_in {
+    () => {
+        r#"
+        test r12, 4
+        jz 6f
+        rdfsbase rax
+        mov [rsp + 8], rax
+        mov rax, [rbx + 8]
+        wrfsbase rax
+    6:
+        "#
+    };
+}
+#[cfg(not(target_feature = "fsgsbase"))]
+macro_rules! swap_tls_in {
+    () => {
+        ""
+    };
+}
+
+/// Swap the `fs` base back in `switch_ext`: save it to `[rbx + 8]`, load it from `[rsp + 8]`.
+#[cfg(target_feature = "fsgsbase")]
+macro_rules! swap_tls_out {
+    () => {
+        r#"
+        test r12, 4
+        jz 7f
+        rdfsbase rax
+        mov [rbx + 8], rax
+        mov rax, [rsp + 8]
+        wrfsbase rax
+    7:
+        "#
+    };
+}
+#[cfg(not(target_feature = "fsgsbase"))]
+macro_rules! swap_tls_out {
+    () => {
+        ""
+    };
+}
+
+impl ThreadContext {
+    /// Call the `switch` at `_switch` with the register groups in the bits of
+    /// `_regs` swapped: the executor ones are kept on this stack, and the
+    /// thread ones are loaded from `_ext` before and saved to it after.
+    ///
+    /// It is one asm block, so no compiled code runs between loading the
+    /// registers of the thread and switching to it.
+    #[naked]
+    #[inline(never)]
+    unsafe extern "sysv64" fn switch_ext(
+        _ptr_ptr: *mut *mut Self,
+        _ext: *mut ExtContext,
+        _regs: usize,
+        _switch: usize,
+    ) {
+        asm!(concat!(r#"
+        push rbx
+        push r12
+        push r13
+        push r14
+        // the executor copy of `ExtContext`, which also keeps the stack
+        // 16-byte aligned at `call`
+        sub rsp, 24
+
+        mov rbx, rsi
+        mov r12, rdx
+        mov r13, rdi
+        mov r14, rcx
+
+        test r12, 2
+        jz 2f
+        fnstcw [rsp + 4]
+        fldcw [rbx + 4]
+    2:
+        test r12, 8
+        jz 3f
+        stmxcsr [rsp]
+        ldmxcsr [rbx]
+    3:
+        "#, swap_tls_in!(), r#"
+        mov rdi, r13
+        call r14
+
+        test r12, 2
+        jz 4f
+        fnstcw [rbx + 4]
+        fldcw [rsp + 4]
+    4:
+        test r12, 8
+        jz 5f
+        stmxcsr [rbx]
+        ldmxcsr [rsp]
+    5:
+        "#, swap_tls_out!(), r#"
+        add rsp, 24
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        "#) :::: "volatile" "intel");
+    }
+}
+
+impl ExtContext {
+    /// Save the register groups in `regs` from the CPU, which a new thread
+    /// starts with.
+    #[inline(always)]
+    unsafe fn save(&mut self, regs: RegSet) {
+        if regs.contains(RegSet::FP) {
+            asm!("fnstcw [$0]" :: "r"(&mut self.fpu_cw) : "memory" : "volatile" "intel");
+        }
+        if regs.contains(RegSet::VECTOR) {
+            asm!("stmxcsr [$0]" :: "r"(&mut self.mxcsr) : "memory" : "volatile" "intel");
+        }
+        #[cfg(target_feature = "fsgsbase")]
+        if regs.contains(RegSet::TLS) {
+            asm!("rdfsbase $0" : "=r"(self.fs_base) ::: "volatile" "intel");
+        }
+    }
+}
+
+/// Number of registers set by `switch_marked`.
+#[cfg(test)]
+const CALLEE_SAVED: usize = 6;
+
+/// Set callee-saved registers to `base + i` in the order of `ThreadContext`,
+/// call `switch` with `ptr_ptr`, then store the registers found after
+/// switching back to `out`. It is used by the conformance tests.
+#[cfg(test)]
+#[naked]
+#[inline(never)]
+unsafe extern "sysv64" fn switch_marked(
+    _ptr_ptr: *mut *mut ThreadContext,
+    _out: *mut usize,
+    _base: usize,
+    _switch: usize,
+) {
+    asm!(r#"
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    // save `out`, which also keeps the stack 16-byte aligned at `call`
+    push rsi
+
+    mov rbx, rdx
+    lea rbp, [rdx + 1]
+    lea r12, [rdx + 2]
+    lea r13, [rdx + 3]
+    lea r14, [rdx + 4]
+    lea r15, [rdx + 5]
+    call rcx
+
+    pop rsi
+    mov [rsi], rbx
+    mov [rsi + 8], rbp
+    mov [rsi + 16], r12
+    mov [rsi + 24], r13
+    mov [rsi + 32], r14
+    mov [rsi + 40], r15
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    "# :::: "volatile" "intel");
 }
 
 /// Get stack pointer.
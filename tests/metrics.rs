@@ -0,0 +1,25 @@
+//! The switch counter is global, so it is tested alone in its own process:
+//!
+//! ```sh
+//! cargo test --features metrics --test metrics
+//! ```
+
+#![cfg(feature = "metrics")]
+
+use greenthread_future::{block_on, total_context_switches, yield_now, ThreadFuture};
+
+#[test]
+fn switches_of_yielding_threads() {
+    const YIELDS: usize = 5;
+    fn yielding() {
+        for _ in 0..YIELDS {
+            yield_now();
+        }
+    }
+
+    let before = total_context_switches();
+    block_on(ThreadFuture::from(yielding as fn()));
+    block_on(ThreadFuture::from(yielding as fn()));
+    // each poll switches in and back, and the last one runs to the exit
+    assert_eq!(total_context_switches() - before, 2 * 2 * (YIELDS + 1));
+}
@@ -0,0 +1,24 @@
+//! With `overflow-mpu`, a thread needs the MPU configurator, which is global,
+//! so it is tested alone in its own process:
+//!
+//! ```sh
+//! cargo test --no-default-features --features overflow-mpu --test overflow_mpu
+//! ```
+
+#![cfg(feature = "overflow-mpu")]
+
+use greenthread_future::{block_on, set_mpu_configurator, yield_now, ThreadFuture};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+fn mpu_only() {
+    static CONFIGURED: AtomicUsize = AtomicUsize::new(0);
+
+    // a thread without any protection is refused
+    let refused = std::panic::catch_unwind(|| block_on(ThreadFuture::from(yield_now)));
+    assert!(refused.is_err());
+
+    set_mpu_configurator(|_base, size| CONFIGURED.store(size, Ordering::SeqCst));
+    block_on(ThreadFuture::from(yield_now));
+    assert_ne!(CONFIGURED.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,42 @@
+//! The stack budget is global, so it is tested alone in its own process.
+
+use greenthread_future::{set_stack_budget, total_stack_bytes, ThreadFuture};
+
+const THREAD_SIZE: usize = 0x2000;
+
+#[test]
+fn stack_budget() {
+    assert_eq!(total_stack_bytes(), 0);
+    set_stack_budget(2 * THREAD_SIZE);
+
+    let first = ThreadFuture::try_new(|| 1u32).ok().unwrap();
+    let second = ThreadFuture::try_new(|| 2u32).ok().unwrap();
+    assert_eq!(total_stack_bytes(), 2 * THREAD_SIZE);
+    assert!(ThreadFuture::try_new(|| 3u32).is_err());
+
+    // freeing a thread makes room for another
+    drop(first);
+    assert_eq!(total_stack_bytes(), THREAD_SIZE);
+    let third = ThreadFuture::try_new(|| 3u32).ok().unwrap();
+    assert_eq!(total_stack_bytes(), 2 * THREAD_SIZE);
+
+    drop(second);
+    drop(third);
+    assert_eq!(total_stack_bytes(), 0);
+
+    // each of them holds at most one thread at a time, so all fit
+    let workers: Vec<_> = (0..2)
+        .map(|_| {
+            std::thread::spawn(|| {
+                for i in 0..1000u32 {
+                    let thread = ThreadFuture::try_new(move || i);
+                    assert!(thread.is_ok(), "over the budget");
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(total_stack_bytes(), 0);
+}